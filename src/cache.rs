@@ -95,9 +95,13 @@ impl DnsCache {
     }
 
     pub fn update(&mut self, response: dns::DnsPacket) {
-        let cache_key = response.question_section[0].clone();
+        let cache_key = match response.question_section.first() {
+            Some(question) => question.clone(),
+            None => return,
+        };
 
-        if response.answer_section.is_empty() {
+        // A truncated answer is partial, and the client is about to retry over TCP anyway
+        if response.answer_section.is_empty() || response.header.istruncated() {
             return;
         }
 
@@ -156,7 +160,7 @@ impl DnsCache {
 
 pub struct DnsCacheManager {
     dns_cache: Arc<Mutex<DnsCache>>,
-    dest_client: Arc<Mutex<dest::DestClient>>,
+    dest_client: Arc<dest::DestClient>,
 
     _prefetch_thread: thread::JoinHandle<()>,
 }
@@ -164,7 +168,7 @@ pub struct DnsCacheManager {
 impl DnsCacheManager {
     pub fn new(dns_cache: DnsCache, dest_client: dest::DestClient) -> Self {
         let dns_cache = Arc::new(Mutex::new(dns_cache));
-        let dest_client = Arc::new(Mutex::new(dest_client));
+        let dest_client = Arc::new(dest_client);
 
         let prefetch_thread = {
             let dns_cache = Arc::clone(&dns_cache);
@@ -181,10 +185,7 @@ impl DnsCacheManager {
                         debug!("Prefetching: {}", expired_dns_question);
                         let request = DnsCacheManager::build_dns_request(expired_dns_question);
 
-                        let response = {
-                            let mut dest_client = dest_client.lock().unwrap();
-                            dest_client.query(request)
-                        };
+                        let response = dest_client.query(request);
 
                         let mut dns_cache = dns_cache.lock().unwrap();
                         dns_cache.update(response.clone());
@@ -211,25 +212,29 @@ impl DnsCacheManager {
         request: &dns::DnsPacket,
         answers: Vec<dns::DnsAnswerSection>,
     ) -> dns::DnsPacket {
-        let mut response = request.clone();
+        let mut response = dns::DnsPacket::new_response(request, dns::RCODE_NOERROR);
         response.add_to_answer_section(&answers);
         response
     }
 
-    pub fn query(&mut self, request: dns::DnsPacket) -> dns::DnsPacket {
-        let mut dns_cache = self.dns_cache.lock().unwrap();
+    /// Answers from the cache, or from the upstream on a miss. The cache is only locked around
+    /// lookups and updates so other queries are not held up by upstream I/O.
+    pub fn query(&self, request: dns::DnsPacket) -> dns::DnsPacket {
+        let cached_answers = {
+            let dns_cache = self.dns_cache.lock().unwrap();
+            dns_cache.query(&request)
+        };
 
-        match dns_cache.query(&request) {
+        match cached_answers {
             Some(cached_answers) => {
                 info!("Cache HIT: {} <-- CACHE", request.header.id);
                 DnsCacheManager::build_dns_response(&request, cached_answers)
             }
             None => {
                 debug!("Cache MISS: {}", request.header.id);
-                let response = {
-                    let mut dest_client = self.dest_client.lock().unwrap();
-                    dest_client.query(request)
-                };
+                let response = self.dest_client.query(request);
+
+                let mut dns_cache = self.dns_cache.lock().unwrap();
                 dns_cache.update(response.clone());
                 response
             }
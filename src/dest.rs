@@ -1,207 +1,2819 @@
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, UdpSocket};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use byteorder::{ByteOrder, NetworkEndian};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use log::*;
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use retry::delay::Exponential;
+use retry::retry;
+use serde::Deserialize;
 
 use crate::dns;
+use crate::framing::{self, StdioFormat, STDIO_ADDR};
+use crate::pool::WorkerPool;
 
-const DEFAULT_LOCAL_DNS_PORT: u16 = 5354;
+pub(crate) const DEFAULT_DNS_PORT: u16 = 53;
+pub(crate) const DEFAULT_DOT_PORT: u16 = 853;
 
-const DEFAULT_DNS_PORT: u16 = 53;
-const DEFAULT_DOT_PORT: u16 = 853;
+/// Delay before the first retry of a failed query, doubling for each further retry
+const RETRY_BACKOFF_MILLIS: u64 = 100;
+const DOH_CONTENT_TYPE: &str = "application/dns-message";
+/// Content types of JSON DNS API answers, the first of which is asked for
+const DOH_JSON_CONTENT_TYPES: &[&str] = &["application/dns-json", "application/json"];
+/// How long idle DoH connections are kept open for reuse
+const DOH_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const DOH_TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+/// Bounds on how long bootstrap results are used, whatever their TTL
+const BOOTSTRAP_MIN_TTL: Duration = Duration::from_secs(60);
+const BOOTSTRAP_MAX_TTL: Duration = Duration::from_secs(3600);
+/// How long stale bootstrap results are kept using after failing to refresh them
+const BOOTSTRAP_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
 
-trait DnsDest {
-    fn query(&mut self, request: dns::DnsPacket) -> dns::DnsPacket;
+/// Each new RTT sample moves the smoothed RTT 1/8th of the way, as TCP does (RFC 6298)
+const SRTT_SAMPLE_DIVISOR: u32 = 8;
+/// Failed attempts and SERVFAIL answers double the smoothed RTT, starting from this when there
+/// is none yet
+const SRTT_FAILURE_PENALTY: Duration = Duration::from_secs(1);
+const SRTT_MAX: Duration = Duration::from_secs(10);
+/// How often the fastest strategy tries another upstream first, so its SRTT stays current
+const EXPLORE_PROBABILITY: f64 = 0.05;
+/// Threads shared by every racing query; more queries wait for one to be free
+const RACE_MAX_THREADS: usize = 64;
+const RACE_MAX_QUEUED: usize = 256;
+
+/// Settings shared by every upstream.
+#[derive(Debug, Clone)]
+pub struct DestOptions {
+    /// How long each attempt waits for an answer
+    pub timeout: Duration,
+    /// How many more attempts an upstream gets before the query moves on
+    pub retries: usize,
+    /// Whether DoT queries ask the upstream how long it keeps idle connections open
+    pub keepalive: bool,
+    /// How DoT upstreams are authenticated, and how they authenticate us
+    pub tls_config: Arc<rustls::ClientConfig>,
+    pub doh_method: DohMethod,
+    pub doh_http_version: HttpVersion,
+    /// Plain DNS server resolving DoH hostnames instead of the system resolver
+    pub bootstrap: Option<SocketAddr>,
 }
 
-struct DnsClient {
-    local_socket: UdpSocket,
-    remote_socket_addr: SocketAddr,
+/// How DoH queries are sent (RFC 8484 4.1).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DohMethod {
+    /// The query is the request body
+    Post,
+    /// The query is base64url encoded into the `dns` parameter, so HTTP caches can answer it
+    Get,
 }
 
-impl DnsClient {
-    fn new<S: Into<String>>(addr: S) -> Self {
-        let mut addr: String = addr.into();
+impl FromStr for DohMethod {
+    type Err = String;
 
-        let local_socket_addr = format!("0.0.0.0:{DEFAULT_LOCAL_DNS_PORT}");
-        let local_socket = UdpSocket::bind(&local_socket_addr).unwrap_or_else(|error| {
-            panic!("Failed to bind UDP socket `{local_socket_addr}`: {error}",)
-        });
+    fn from_str(method: &str) -> Result<Self, Self::Err> {
+        match method {
+            "post" => Ok(DohMethod::Post),
+            "get" => Ok(DohMethod::Get),
+            _ => Err(format!(
+                "Unknown DoH method `{method}`, expected `post` or `get`"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DohMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DohMethod::Post => write!(f, "post"),
+            DohMethod::Get => write!(f, "get"),
+        }
+    }
+}
+
+/// Which HTTP version DoH upstreams are spoken to with.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HttpVersion {
+    /// HTTP/2 when the upstream offers it through ALPN, otherwise HTTP/1.1
+    Auto,
+    Http1,
+    /// HTTP/2 only, multiplexing every query over one connection
+    Http2,
+}
+
+impl FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        match version {
+            "auto" => Ok(HttpVersion::Auto),
+            "1.1" => Ok(HttpVersion::Http1),
+            "2" => Ok(HttpVersion::Http2),
+            _ => Err(format!(
+                "Unknown HTTP version `{version}`, expected `auto`, `1.1` or `2`"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpVersion::Auto => write!(f, "auto"),
+            HttpVersion::Http1 => write!(f, "1.1"),
+            HttpVersion::Http2 => write!(f, "2"),
+        }
+    }
+}
+
+/// A parsed `--dest` address.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DestSpec {
+    /// `-`
+    Stdio,
+    /// `[udp://]ADDR`
+    Udp(SocketAddr),
+    /// `tcp://ADDR`
+    Tcp(SocketAddr),
+    /// `tls://ADDR[#HOSTNAME]` or `ADDR#HOSTNAME`, verifying the certificate against the
+    /// hostname, or against the IP address when it is left out
+    Tls { addr: SocketAddr, hostname: String },
+    /// `https://HOSTNAME[:PORT]/PATH[@IP,IP...]`, connecting to the given addresses instead of
+    /// resolving the hostname
+    Https { url: String, addrs: Vec<IpAddr> },
+    /// `json+https://HOSTNAME[:PORT]/PATH[@IP,IP...]`, a JSON DNS API rather than RFC 8484
+    Json { url: String, addrs: Vec<IpAddr> },
+}
+
+/// Parses a plain DNS server address, `IP[:PORT]`.
+pub fn parse_dns_server(addr: &str) -> Result<SocketAddr, String> {
+    parse_socket_addr(addr, DEFAULT_DNS_PORT)
+}
+
+/// Parses `IP`, `IP:PORT`, `[IPV6]` or `[IPV6]:PORT`.
+pub(crate) fn parse_socket_addr(addr: &str, default_port: u16) -> Result<SocketAddr, String> {
+    if let Ok(socket_addr) = SocketAddr::from_str(addr) {
+        return Ok(socket_addr);
+    }
+
+    let ip = addr
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(addr);
+    IpAddr::from_str(ip)
+        .map(|ip| SocketAddr::new(ip, default_port))
+        .map_err(|_| format!("`{addr}` is not an IP address with an optional port"))
+}
+
+impl DestSpec {
+    fn parse_tls(addr: &str) -> Result<Self, String> {
+        let (addr, hostname) = match addr.split_once('#') {
+            Some((_, "")) => return Err("Missing hostname after `#`".to_string()),
+            Some((addr, hostname)) => (parse_socket_addr(addr, DEFAULT_DOT_PORT)?, hostname),
+            None => {
+                let addr = parse_socket_addr(addr, DEFAULT_DOT_PORT)?;
+                (addr, &*addr.ip().to_string())
+            }
+        };
+
+        rustls::pki_types::ServerName::try_from(hostname)
+            .map_err(|_| format!("`{hostname}` is not a valid TLS server name"))?;
+
+        Ok(DestSpec::Tls {
+            addr,
+            hostname: hostname.to_string(),
+        })
+    }
+
+    /// An HTTPS URL, and the addresses to connect to instead of resolving its host.
+    fn parse_https(url: &str) -> Result<(String, Vec<IpAddr>), String> {
+        // Only a trailing `@IP,IP...` after the path lists addresses, so an `@` in the user info
+        // or the query stays part of the URL
+        let is_bootstrap_suffix = |url: &str, addrs: &str| {
+            let has_path = url
+                .split_once("://")
+                .is_some_and(|(_, rest)| rest.contains('/'));
+            let is_addr_list = addrs.contains(['.', ':'])
+                && addrs
+                    .chars()
+                    .all(|c| c.is_ascii_hexdigit() || matches!(c, '.' | ':' | ','));
+            has_path && is_addr_list
+        };
+        let (url, addrs) = match url.rsplit_once('@') {
+            Some((url, addrs)) if is_bootstrap_suffix(url, addrs) => {
+                let addrs = addrs
+                    .split(',')
+                    .map(|addr| {
+                        IpAddr::from_str(addr).map_err(|_| format!("`{addr}` is not an IP address"))
+                    })
+                    .collect::<Result<Vec<IpAddr>, String>>()?;
+                (url, addrs)
+            }
+            _ => (url, vec![]),
+        };
+
+        let parsed_url =
+            reqwest::Url::parse(url).map_err(|error| format!("Bad URL `{url}`: {error}"))?;
+        if !parsed_url.has_host() {
+            return Err(format!("URL `{url}` has no host"));
+        }
+
+        Ok((url.to_string(), addrs))
+    }
+}
+
+impl FromStr for DestSpec {
+    type Err = String;
+
+    fn from_str(dest: &str) -> Result<Self, Self::Err> {
+        let result = match dest.split_once("://") {
+            Some(("udp", addr)) => parse_socket_addr(addr, DEFAULT_DNS_PORT).map(DestSpec::Udp),
+            Some(("tcp", addr)) => parse_socket_addr(addr, DEFAULT_DNS_PORT).map(DestSpec::Tcp),
+            Some(("tls", addr)) => DestSpec::parse_tls(addr),
+            Some(("https", _)) => {
+                DestSpec::parse_https(dest).map(|(url, addrs)| DestSpec::Https { url, addrs })
+            }
+            Some(("json+https", _)) => DestSpec::parse_https(dest.trim_start_matches("json+"))
+                .map(|(url, addrs)| DestSpec::Json { url, addrs }),
+            Some((scheme, _)) => Err(format!(
+                "Unknown scheme `{scheme}`, expected `udp`, `tcp`, `tls`, `https` or `json+https`"
+            )),
+            None if dest == STDIO_ADDR => Ok(DestSpec::Stdio),
+            None if dest.contains('#') => DestSpec::parse_tls(dest),
+            None => parse_socket_addr(dest, DEFAULT_DNS_PORT).map(DestSpec::Udp),
+        };
+
+        result.map_err(|error| format!("Bad destination `{dest}`: {error}"))
+    }
+}
+
+impl fmt::Display for DestSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DestSpec::Stdio => write!(f, "{STDIO_ADDR}"),
+            DestSpec::Udp(addr) => write!(f, "udp://{addr}"),
+            DestSpec::Tcp(addr) => write!(f, "tcp://{addr}"),
+            DestSpec::Tls { addr, hostname } => write!(f, "tls://{addr}#{hostname}"),
+            DestSpec::Https { url, addrs } => write!(f, "{url}{}", AddrsSuffix(addrs)),
+            DestSpec::Json { url, addrs } => write!(f, "json+{url}{}", AddrsSuffix(addrs)),
+        }
+    }
+}
+
+/// A parsed `--forward` rule, `SUFFIX=DEST`, sending queries for the domain `SUFFIX` and the
+/// names below it to `DEST` instead of the default destinations.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ForwardRule {
+    pub suffix: dns::DnsName,
+    pub dest: DestSpec,
+}
+
+impl FromStr for ForwardRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let (suffix, dest) = rule
+            .split_once('=')
+            .ok_or_else(|| format!("Bad forward rule `{rule}`: expected `SUFFIX=DEST`"))?;
+
+        // `*.corp.internal` reads naturally, but means the same as `corp.internal`
+        let suffix = suffix.strip_prefix("*.").unwrap_or(suffix);
+        let suffix = dns::DnsName::from_str(suffix)
+            .map_err(|error| format!("Bad forward rule `{rule}`: {error}"))?;
+
+        Ok(ForwardRule {
+            suffix,
+            dest: dest.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for ForwardRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.suffix, self.dest)
+    }
+}
 
-        if addr.find(':').is_none() {
-            addr.push_str(&format!(":{DEFAULT_DNS_PORT}"));
+/// Writes the `@IP,IP...` suffix of HTTPS destinations, if there are any addresses.
+struct AddrsSuffix<'a>(&'a [IpAddr]);
+
+impl fmt::Display for AddrsSuffix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        let addrs: Vec<String> = self.0.iter().map(IpAddr::to_string).collect();
+        write!(f, "@{}", addrs.join(","))
+    }
+}
+
+/// How each query picks among the upstreams that are up.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Strategy {
+    /// The first upstream, then the next ones in order
+    Failover,
+    /// Each query starts at the upstream after the previous query's
+    RoundRobin,
+    /// Upstreams in a random order, weighted by `--weights`
+    Random,
+    /// Lowest smoothed RTT first, occasionally exploring the others
+    Fastest,
+    /// All upstreams at once, taking the first answer
+    Race,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        match strategy {
+            "failover" => Ok(Strategy::Failover),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "random" => Ok(Strategy::Random),
+            "fastest" => Ok(Strategy::Fastest),
+            "race" => Ok(Strategy::Race),
+            _ => Err(format!(
+                "Unknown strategy `{strategy}`, expected `failover`, `round-robin`, `random`, `fastest` or `race`"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::Failover => write!(f, "failover"),
+            Strategy::RoundRobin => write!(f, "round-robin"),
+            Strategy::Random => write!(f, "random"),
+            Strategy::Fastest => write!(f, "fastest"),
+            Strategy::Race => write!(f, "race"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DestError {
+    Timeout,
+    Io(io::Error),
+    Http(reqwest::Error),
+    HttpStatus(reqwest::StatusCode),
+    ContentType(String),
+    Json(String),
+    Parse(dns::DnsParseError),
+    NotAResponse,
+    Mismatched,
+}
+
+impl fmt::Display for DestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DestError::Timeout => write!(f, "timed out"),
+            DestError::Io(error) => write!(f, "{error}"),
+            DestError::Http(error) => write!(f, "{error}"),
+            DestError::HttpStatus(status) => write!(f, "upstream answered HTTP {status}"),
+            DestError::ContentType(content_type) => {
+                write!(f, "upstream answered with content type `{content_type}`")
+            }
+            DestError::Json(error) => write!(f, "failed to read JSON response: {error}"),
+            DestError::Parse(error) => write!(f, "failed to parse response: {error}"),
+            DestError::NotAResponse => write!(f, "upstream sent a request instead of a response"),
+            DestError::Mismatched => {
+                write!(f, "response ID or question does not match the request")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DestError {}
+
+impl From<io::Error> for DestError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => DestError::Timeout,
+            _ => DestError::Io(error),
+        }
+    }
+}
+
+impl From<reqwest::Error> for DestError {
+    fn from(error: reqwest::Error) -> Self {
+        match error.is_timeout() {
+            true => DestError::Timeout,
+            _ => DestError::Http(error),
         }
+    }
+}
 
-        let remote_socket_addr = SocketAddr::from_str(&addr)
-            .unwrap_or_else(|error| panic!("Failed parse socket address `{addr}`: {error}",));
+impl From<dns::DnsParseError> for DestError {
+    fn from(error: dns::DnsParseError) -> Self {
+        DestError::Parse(error)
+    }
+}
+
+/// Upstreams are shared between worker threads, so any connection state is locked internally.
+pub(crate) trait DnsDest {
+    fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError>;
+}
+
+fn parse_response(response_payload: &[u8]) -> Result<dns::DnsPacket, DestError> {
+    let response = dns::DnsPacket::from_slice(response_payload)?;
+    if response.header.isrequest() {
+        return Err(DestError::NotAResponse);
+    }
+
+    Ok(response)
+}
 
+/// Sends each query from a fresh socket, so every query gets its own random source port
+/// from the OS (RFC 5452 9.2). Truncated answers are fetched again over TCP, which can also be
+/// used for every query where UDP is filtered.
+struct DnsClient {
+    remote_socket_addr: SocketAddr,
+    timeout: Duration,
+    tcp_only: bool,
+}
+
+impl DnsClient {
+    fn new(remote_socket_addr: SocketAddr, timeout: Duration, tcp_only: bool) -> Self {
         Self {
-            local_socket,
             remote_socket_addr,
+            timeout,
+            tcp_only,
+        }
+    }
+
+    fn query_udp(&self, request: &dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+        let local_socket_addr = match self.remote_socket_addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let local_socket = UdpSocket::bind(local_socket_addr)?;
+        local_socket.send_to(&request.bytes(), self.remote_socket_addr)?;
+
+        // Late answers to earlier queries and datagrams from anywhere but the upstream are
+        // skipped, waiting on for the real answer until the timeout (RFC 5452 9.1)
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = vec![0u8; dns::EDNS_UDP_PAYLOAD_SIZE.into()];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(DestError::Timeout);
+            }
+            local_socket.set_read_timeout(Some(remaining))?;
+
+            let (number_of_bytes, src_addr) = local_socket.recv_from(&mut buf)?;
+            if src_addr != self.remote_socket_addr {
+                warn!("Discarding datagram from unexpected address {src_addr}");
+                continue;
+            }
+
+            match parse_response(&buf[..number_of_bytes]) {
+                Ok(response) if response.is_response_to(request) => return Ok(response),
+                Ok(response) => warn!(
+                    "Discarding mismatched response {} from {src_addr}",
+                    response.header.id
+                ),
+                Err(error) => warn!("Discarding bad response from {src_addr}: {error}"),
+            }
         }
     }
+
+    fn query_tcp(&self, request: &dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+        let mut stream = TcpStream::connect_timeout(&self.remote_socket_addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        framing::write_length_prefixed(&mut stream, &request.bytes())?;
+        let response_payload = framing::read_length_prefixed(&mut stream)?;
+
+        parse_response(&response_payload)
+    }
 }
 
 impl DnsDest for DnsClient {
-    fn query(&mut self, request: dns::DnsPacket) -> dns::DnsPacket {
-        self.local_socket
-            .send_to(&request.bytes(), self.remote_socket_addr)
-            .unwrap();
+    fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+        if self.tcp_only {
+            return self.query_tcp(&request);
+        }
+
+        let response = self.query_udp(&request)?;
+        if !response.header.istruncated() {
+            return Ok(response);
+        }
+
+        debug!(
+            "Response {} from {} is truncated, retrying over TCP",
+            response.header.id, self.remote_socket_addr
+        );
+        self.query_tcp(&request)
+    }
+}
+
+/// How many connections each DoT upstream spreads its queries over
+const DOT_POOL_SIZE: usize = 2;
+const DOT_READ_BUFFER_LEN: usize = 16384;
+
+/// When the last query went out and the last response came back on a DoT connection, and the
+/// idle timeout the upstream advertised through edns-tcp-keepalive (RFC 7828).
+struct DotActivity {
+    last_sent: Instant,
+    last_received: Instant,
+    idle_timeout: Option<Duration>,
+}
+
+/// State shared between the threads sending queries and the thread reading responses.
+struct DotShared {
+    socket: TcpStream,
+    tls_conn: Mutex<rustls::ClientConnection>,
+    pending: Mutex<HashMap<u16, mpsc::Sender<Result<dns::DnsPacket, DestError>>>>,
+    activity: Mutex<DotActivity>,
+    is_open: AtomicBool,
+}
+
+impl DotShared {
+    fn close(&self) {
+        self.is_open.store(false, Ordering::Relaxed);
+        // Wakes the reader thread, which then fails every pending query
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+
+    /// Answers the pending query with the response's ID.
+    fn dispatch(&self, frame: &[u8]) {
+        let [id_high, id_low, ..] = *frame else {
+            warn!("Ignoring {} byte DoT response", frame.len());
+            return;
+        };
+        let id = u16::from_be_bytes([id_high, id_low]);
+
+        let result = parse_response(frame);
+        {
+            let mut activity = self.activity.lock().unwrap();
+            activity.last_received = Instant::now();
+            if let Some(idle_timeout) = result
+                .as_ref()
+                .ok()
+                .and_then(|response| response.edns.as_ref()?.tcp_keepalive())
+            {
+                activity.idle_timeout = Some(idle_timeout);
+            }
+        }
+
+        match self.pending.lock().unwrap().remove(&id) {
+            Some(sender) => {
+                let _ = sender.send(result);
+            }
+            None => debug!("Dropping DoT response {id} with no pending query"),
+        }
+    }
+
+    /// Reads responses until the connection closes, then fails the queries still pending.
+    fn read_responses(&self) {
+        let mut buf = [0u8; DOT_READ_BUFFER_LEN];
+        let mut plaintext: Vec<u8> = Vec::new();
+
+        let error = loop {
+            // The TLS state is only locked once data has arrived, so queries can be sent meanwhile
+            let len = match (&self.socket).read(&mut buf) {
+                Ok(0) => break io::Error::from(io::ErrorKind::UnexpectedEof),
+                Ok(len) => len,
+                Err(error) => break error,
+            };
+
+            if let Err(error) = self.read_tls(&buf[..len], &mut plaintext) {
+                break error;
+            }
+
+            while plaintext.len() >= 2 {
+                let frame_len = usize::from(u16::from_be_bytes([plaintext[0], plaintext[1]]));
+                if plaintext.len() < 2 + frame_len {
+                    break;
+                }
+                let frame: Vec<u8> = plaintext.drain(..2 + frame_len).skip(2).collect();
+                self.dispatch(&frame);
+            }
+        };
+
+        debug!("DoT connection closed: {error}");
+        self.close();
+        for (_, sender) in self.pending.lock().unwrap().drain() {
+            let _ = sender.send(Err(DestError::Io(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("DoT connection closed: {error}"),
+            ))));
+        }
+    }
+
+    /// Decrypts `data` read from the socket, appending the plaintext.
+    fn read_tls(&self, data: &[u8], plaintext: &mut Vec<u8>) -> io::Result<()> {
+        let mut tls_conn = self.tls_conn.lock().unwrap();
+        let mut data = data;
+        while !data.is_empty() {
+            tls_conn.read_tls(&mut data)?;
+            tls_conn
+                .process_new_packets()
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        }
+
+        // Reading stops with WouldBlock once the decrypted data is used up
+        match tls_conn.reader().read_to_end(plaintext) {
+            Ok(_) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => {}
+            Err(error) => return Err(error),
+        }
+
+        // Alerts and key updates
+        while tls_conn.wants_write() {
+            tls_conn.write_tls(&mut &self.socket)?;
+        }
+        Ok(())
+    }
+}
+
+/// A DoT connection that carries several queries at once, matching responses to them by
+/// message ID so they can arrive in any order (RFC 7766 6.2.1.1).
+struct DotConnection {
+    shared: Arc<DotShared>,
+}
+
+impl DotConnection {
+    fn new(socket: TcpStream, tls_conn: rustls::ClientConnection) -> io::Result<Self> {
+        let now = Instant::now();
+        let shared = Arc::new(DotShared {
+            socket: socket.try_clone()?,
+            tls_conn: Mutex::new(tls_conn),
+            pending: Mutex::new(HashMap::new()),
+            activity: Mutex::new(DotActivity {
+                last_sent: now,
+                last_received: now,
+                idle_timeout: None,
+            }),
+            is_open: AtomicBool::new(true),
+        });
+
+        let reader_shared = shared.clone();
+        thread::spawn(move || reader_shared.read_responses());
+
+        Ok(Self { shared })
+    }
+
+    /// Whether the connection is open and hasn't sat idle past the upstream's keepalive timeout.
+    fn is_usable(&self) -> bool {
+        let activity = self.shared.activity.lock().unwrap();
+        let last_used = activity.last_sent.max(activity.last_received);
+        let is_expired = activity
+            .idle_timeout
+            .is_some_and(|idle_timeout| last_used.elapsed() >= idle_timeout);
+
+        self.shared.is_open.load(Ordering::Relaxed) && !is_expired
+    }
+
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        let mut tls_conn = self.shared.tls_conn.lock().unwrap();
+        framing::write_length_prefixed(&mut tls_conn.writer(), message)?;
+        while tls_conn.wants_write() {
+            tls_conn.write_tls(&mut &self.shared.socket)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the query under an ID that is unique on this connection, then waits for its answer.
+    fn query(
+        &self,
+        request: &dns::DnsPacket,
+        timeout: Duration,
+    ) -> Result<dns::DnsPacket, DestError> {
+        let (sender, receiver) = mpsc::channel();
+        let id = {
+            let mut pending = self.shared.pending.lock().unwrap();
+            let mut id: u16 = random();
+            while pending.contains_key(&id) {
+                id = random();
+            }
+            pending.insert(id, sender);
+            id
+        };
+
+        let original_id = request.header.id;
+        let mut request = request.clone();
+        request.header.id = id;
+        let sent_time = Instant::now();
+        self.shared.activity.lock().unwrap().last_sent = sent_time;
+
+        let result = match self.send(&request.bytes()) {
+            Ok(()) => receiver
+                .recv_timeout(timeout)
+                .unwrap_or_else(|error| match error {
+                    mpsc::RecvTimeoutError::Timeout => Err(DestError::Timeout),
+                    mpsc::RecvTimeoutError::Disconnected => Err(DestError::Io(io::Error::from(
+                        io::ErrorKind::ConnectionAborted,
+                    ))),
+                }),
+            Err(error) => {
+                self.shared.close();
+                Err(error.into())
+            }
+        };
+        self.shared.pending.lock().unwrap().remove(&id);
 
-        let mut buf = [0; 512];
-        let (_number_of_bytes, _src_addr) = self.local_socket.recv_from(&mut buf).unwrap();
+        // Nothing arriving since the query went out means the connection is stuck
+        if matches!(result, Err(DestError::Timeout))
+            && self.shared.activity.lock().unwrap().last_received < sent_time
+        {
+            debug!("DoT connection is unresponsive");
+            self.shared.close();
+        }
+
+        result.map(|mut response| {
+            response.header.id = original_id;
+            response
+        })
+    }
+}
 
-        dns::DnsPacket::from_slice(&buf)
+impl Drop for DotConnection {
+    fn drop(&mut self) {
+        self.shared.close();
     }
 }
 
+/// Spreads queries over a small pool of pipelined connections, each opened on first use and
+/// reopened whenever it is closed or broken.
 struct DotClient {
-    tls_stream: rustls::StreamOwned<rustls::ClientConnection, TcpStream>,
+    socket_addr: SocketAddr,
+    server_name: rustls::pki_types::ServerName<'static>,
+    config: Arc<rustls::ClientConfig>,
+    timeout: Duration,
+    keepalive: bool,
+    pool: Vec<Mutex<Option<Arc<DotConnection>>>>,
+    next_connection: AtomicUsize,
 }
 
 impl DotClient {
-    fn new<S: AsRef<str>>(addr: S, hostname: S) -> Self {
+    fn new(socket_addr: SocketAddr, hostname: &str, options: &DestOptions) -> Self {
+        let server_name = hostname
+            .to_string()
+            .try_into()
+            .unwrap_or_else(|error| panic!("Failed parse hostname `{hostname}`: {error}"));
+
         Self {
-            tls_stream: Self::get_tls_connection(addr, hostname),
+            socket_addr,
+            server_name,
+            config: options.tls_config.clone(),
+            timeout: options.timeout,
+            keepalive: options.keepalive,
+            pool: (0..DOT_POOL_SIZE).map(|_| Mutex::new(None)).collect(),
+            next_connection: AtomicUsize::new(0),
         }
     }
 
-    fn get_tls_connection<S: AsRef<str>>(
-        addr: S,
-        hostname: S,
-    ) -> rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
-        let root_store =
-            rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-        let config = rustls::ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+    fn connect(&self) -> Result<DotConnection, DestError> {
+        debug!("Connecting to DoT upstream {}", self.socket_addr);
+        let mut tls_conn =
+            rustls::ClientConnection::new(self.config.clone(), self.server_name.clone())
+                .unwrap_or_else(|error| panic!("Failed to create TLS client connection: {error}"));
 
-        let server_name = String::from(hostname.as_ref())
-            .try_into()
-            .unwrap_or_else(|error| {
-                panic!("Failed parse hostname `{}`: {error}", hostname.as_ref())
-            });
-        let conn = rustls::ClientConnection::new(Arc::new(config), server_name)
-            .unwrap_or_else(|error| panic!("Failed to create TLS client connection: {error}"));
+        let mut socket = TcpStream::connect_timeout(&self.socket_addr, self.timeout)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.set_write_timeout(Some(self.timeout))?;
+        socket.set_nodelay(true)?;
 
-        let addr = format!("{}:{}", addr.as_ref(), DEFAULT_DOT_PORT);
-        let sock = TcpStream::connect(&addr).unwrap_or_else(|error| {
-            panic!("Failed to create TCP socket connection to `{addr}`: {error}")
-        });
+        while tls_conn.is_handshaking() {
+            tls_conn.complete_io(&mut socket)?;
+        }
+        // Queries time out on their own, so the reader thread waits as long as the connection lasts
+        socket.set_read_timeout(None)?;
+
+        Ok(DotConnection::new(socket, tls_conn)?)
+    }
 
-        rustls::StreamOwned::new(conn, sock)
+    /// The next connection in the pool, opening it if needed. Also tells whether it was reused.
+    fn connection(&self) -> Result<(Arc<DotConnection>, bool), DestError> {
+        let index = self.next_connection.fetch_add(1, Ordering::Relaxed) % self.pool.len();
+        let mut slot = self.pool[index].lock().unwrap();
+
+        match slot.as_ref() {
+            Some(connection) if connection.is_usable() => Ok((connection.clone(), true)),
+            _ => {
+                let connection = Arc::new(self.connect()?);
+                *slot = Some(connection.clone());
+                Ok((connection, false))
+            }
+        }
     }
 }
 
 impl DnsDest for DotClient {
-    fn query(&mut self, request: dns::DnsPacket) -> dns::DnsPacket {
-        let mut request_payload: Vec<u8> = Vec::new();
-        let dns_len_u16: u16 = request.bytes().len() as u16;
-        let mut u16buf = [0; 2];
-        NetworkEndian::write_u16(&mut u16buf, dns_len_u16);
-        request_payload.extend_from_slice(&u16buf);
-        request_payload.extend(request.bytes().iter());
+    fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+        let mut request = request;
+        if self.keepalive {
+            if let Some(edns) = request.edns.as_mut() {
+                edns.request_tcp_keepalive();
+            }
+        }
+
+        // A reused connection may have been closed by the upstream since the last query, in which
+        // case the query is retried once on a fresh connection
+        let (connection, is_reused) = self.connection()?;
+        match connection.query(&request, self.timeout) {
+            Err(DestError::Io(error)) if is_reused => {
+                debug!("DoT connection to {} is broken: {error}", self.socket_addr);
+                let (connection, _) = self.connection()?;
+                connection.query(&request, self.timeout)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Resolves DoH hostnames through a plain DNS server rather than the system resolver, which
+/// may well be ovenrack itself.
+struct Bootstrap {
+    hostname: dns::DnsName,
+    client: DnsClient,
+}
+
+impl Bootstrap {
+    /// The hostname's addresses, and how long they can be used for. Failing to look up one
+    /// address family is fine as long as the other has addresses.
+    fn resolve(&self) -> Result<(Vec<IpAddr>, Duration), DestError> {
+        let mut addrs: Vec<IpAddr> = Vec::new();
+        let mut ttl = BOOTSTRAP_MAX_TTL;
+        let mut lookup_error: Option<DestError> = None;
 
-        self.tls_stream.write_all(&request_payload).unwrap();
+        for qtype in [dns::TYPE_A, dns::TYPE_AAAA] {
+            let question = dns::DnsQuestionSection {
+                qname: self.hostname.clone(),
+                qtype,
+                qclass: dns::CLASS_IN,
+            };
+            let mut request = dns::DnsPacket::new_with_questions(vec![question]);
+            request.header.set_recursion_desired(true);
+
+            let result = self.client.query(request.clone()).and_then(|response| {
+                match response.is_response_to(&request) {
+                    true => Ok(response),
+                    false => Err(DestError::Mismatched),
+                }
+            });
+            let response = match result {
+                Ok(response) => response,
+                Err(error) => {
+                    debug!(
+                        "Bootstrap lookup failed: {} {qtype} ({error})",
+                        self.hostname
+                    );
+                    lookup_error = Some(error);
+                    continue;
+                }
+            };
 
-        let mut reply_len_buff: [u8; 2] = [0; 2];
-        self.tls_stream.read_exact(&mut reply_len_buff).unwrap();
-        let reply_len = NetworkEndian::read_u16(&reply_len_buff);
+            for answer in response.answer_section {
+                let addr: IpAddr = match answer.rdata {
+                    dns::RData::ARecord { ip } => ip.into(),
+                    dns::RData::AAAARecord { ip } => ip.into(),
+                    _ => continue,
+                };
+                addrs.push(addr);
+                ttl = ttl.min(Duration::from_secs(answer.ttl.into()));
+            }
+        }
 
-        let mut response_payload: Vec<u8> = vec![0u8; reply_len.into()];
-        self.tls_stream.read_exact(&mut response_payload).unwrap();
+        if addrs.is_empty() {
+            return Err(lookup_error.unwrap_or_else(|| {
+                DestError::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("bootstrap found no addresses for `{}`", self.hostname),
+                ))
+            }));
+        }
 
-        dns::DnsPacket::from_slice(&response_payload)
+        Ok((addrs, ttl.max(BOOTSTRAP_MIN_TTL)))
     }
 }
 
-struct DohClient {
-    addr: String,
+/// An HTTP client, and when the bootstrap results it connects to need refreshing.
+struct BootstrappedClient {
     client: reqwest::blocking::Client,
+    refresh_time: Option<Instant>,
 }
 
-impl DohClient {
-    fn new<S: Into<String>>(addr: S) -> Self {
-        Self {
-            addr: addr.into(),
-            client: reqwest::blocking::Client::new(),
+/// A pool of reused HTTPS connections to one URL, shared by the wire format and JSON clients.
+struct HttpsClient {
+    url: reqwest::Url,
+    timeout: Duration,
+    http_version: HttpVersion,
+    bootstrap: Option<Bootstrap>,
+    /// Built on the first query when bootstrapping, and rebuilt whenever the addresses expire
+    http_client: Mutex<Option<BootstrappedClient>>,
+}
+
+impl HttpsClient {
+    /// Connects to `addrs` if any are given, otherwise to the addresses of the URL's host as
+    /// found by the bootstrap server, or by the system resolver without one.
+    fn new(url: &str, addrs: &[IpAddr], options: &DestOptions) -> Self {
+        let url = reqwest::Url::parse(url)
+            .unwrap_or_else(|error| panic!("Failed to parse URL `{url}`: {error}"));
+
+        let bootstrap = match (options.bootstrap, url.domain()) {
+            (Some(bootstrap_addr), Some(domain)) if addrs.is_empty() => Some(Bootstrap {
+                hostname: domain
+                    .parse()
+                    .unwrap_or_else(|error| panic!("Failed to parse hostname `{domain}`: {error}")),
+                client: DnsClient::new(bootstrap_addr, options.timeout, false),
+            }),
+            _ => None,
+        };
+
+        let mut https_client = Self {
+            url,
+            timeout: options.timeout,
+            http_version: options.doh_http_version,
+            bootstrap,
+            http_client: Mutex::new(None),
+        };
+        if https_client.bootstrap.is_none() {
+            let client = https_client.build_client(addrs);
+            https_client.http_client = Mutex::new(Some(BootstrappedClient {
+                client,
+                refresh_time: None,
+            }));
+        }
+
+        https_client
+    }
+
+    fn build_client(&self, addrs: &[IpAddr]) -> reqwest::blocking::Client {
+        let builder = reqwest::blocking::Client::builder()
+            .timeout(self.timeout)
+            .connect_timeout(self.timeout)
+            .pool_idle_timeout(DOH_POOL_IDLE_TIMEOUT)
+            .tcp_keepalive(DOH_TCP_KEEPALIVE);
+        let builder = match self.http_version {
+            HttpVersion::Auto => builder,
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge(),
+        };
+
+        let builder = match (self.url.host_str(), self.url.port_or_known_default()) {
+            (Some(host), Some(port)) if !addrs.is_empty() => {
+                let socket_addrs: Vec<SocketAddr> = addrs
+                    .iter()
+                    .map(|addr| SocketAddr::new(*addr, port))
+                    .collect();
+                builder.resolve_to_addrs(host, &socket_addrs)
+            }
+            _ => builder,
+        };
+
+        builder
+            .build()
+            .unwrap_or_else(|error| panic!("Failed to create HTTPS client: {error}"))
+    }
+
+    /// The HTTP client, bootstrapping the host's addresses first if they are missing or expired.
+    /// Expired addresses are kept in use for a while when they can't be refreshed.
+    fn client(&self) -> Result<reqwest::blocking::Client, DestError> {
+        // The lock isn't held while resolving, so queries meanwhile carry on with the expired
+        // addresses rather than waiting, and don't start refreshing them too
+        let stale_client = {
+            let mut http_client = self.http_client.lock().unwrap();
+            let now = Instant::now();
+            match http_client.as_mut() {
+                Some(current) if current.refresh_time.is_some_and(|time| now >= time) => {
+                    current.refresh_time = Some(now + BOOTSTRAP_RETRY_INTERVAL);
+                    Some(current.client.clone())
+                }
+                Some(current) => return Ok(current.client.clone()),
+                None => None,
+            }
+        };
+        let bootstrap = self
+            .bootstrap
+            .as_ref()
+            .expect("Only bootstrapped clients are built late or expire");
+
+        match bootstrap.resolve() {
+            Ok((addrs, ttl)) => {
+                debug!(
+                    "Bootstrapped `{}` to {addrs:?} for {ttl:?}",
+                    bootstrap.hostname
+                );
+                let client = self.build_client(&addrs);
+                *self.http_client.lock().unwrap() = Some(BootstrappedClient {
+                    client: client.clone(),
+                    refresh_time: Some(Instant::now() + ttl),
+                });
+                Ok(client)
+            }
+            Err(error) => {
+                warn!("Failed to bootstrap `{}`: {error}", bootstrap.hostname);
+                stale_client.ok_or(error)
+            }
         }
     }
 }
 
-impl DnsDest for DohClient {
-    fn query(&mut self, request: dns::DnsPacket) -> dns::DnsPacket {
-        let https_response = self
-            .client
-            .post(&self.addr)
-            .header(reqwest::header::ACCEPT, "application/dns-message")
-            .header(reqwest::header::CONTENT_TYPE, "application/dns-message")
-            .body(request.bytes())
-            .send()
-            .unwrap();
+/// Fails unless the upstream answered 200 with one of `content_types`. Error pages are usually
+/// served as 200 text/html by misconfigured proxies.
+fn check_https_response(
+    https_response: reqwest::blocking::Response,
+    content_types: &[&str],
+) -> Result<reqwest::blocking::Response, DestError> {
+    if https_response.status() != reqwest::StatusCode::OK {
+        return Err(DestError::HttpStatus(https_response.status()));
+    }
 
-        dns::DnsPacket::from_slice(&https_response.bytes().unwrap())
+    let content_type = https_response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default();
+    let mime_type = content_type.split(';').next().unwrap_or_default().trim();
+    if !content_types
+        .iter()
+        .any(|expected| mime_type.eq_ignore_ascii_case(expected))
+    {
+        return Err(DestError::ContentType(content_type.to_string()));
     }
+
+    Ok(https_response)
 }
 
-pub struct DestClient {
-    client: Box<dyn DnsDest + Send>,
+/// Sends queries in wire format, with the message ID zeroed so identical queries are
+/// cacheable (RFC 8484 4.1).
+struct DohClient {
+    https: HttpsClient,
+    method: DohMethod,
 }
 
-impl DestClient {
-    pub fn new<S: Into<String>>(addr: S) -> Self {
-        let addr: String = addr.into();
-        let is_tls = addr.contains('#');
-        let is_https = addr.contains("https://");
+impl DohClient {
+    fn new(url: &str, addrs: &[IpAddr], options: &DestOptions) -> Self {
+        Self {
+            https: HttpsClient::new(url, addrs, options),
+            method: options.doh_method,
+        }
+    }
 
-        if is_https {
-            info!("Protocol: DoH");
-            Self {
-                client: Box::new(DohClient::new(addr)),
+    fn send(&self, message: Vec<u8>) -> Result<reqwest::blocking::Response, DestError> {
+        let client = self.https.client()?;
+        let http_request = match self.method {
+            DohMethod::Post => client
+                .post(self.https.url.clone())
+                .header(reqwest::header::CONTENT_TYPE, DOH_CONTENT_TYPE)
+                .body(message),
+            DohMethod::Get => {
+                let mut url = self.https.url.clone();
+                url.query_pairs_mut()
+                    .append_pair("dns", &URL_SAFE_NO_PAD.encode(message));
+                client.get(url)
             }
-        } else if is_tls {
-            info!("Protocol: DoT");
-            let addr_parts: Vec<&str> = addr.split('#').collect();
-            let socket_addr = addr_parts[0].to_string();
-            let hostname = addr_parts[1].to_string();
+        };
 
-            Self {
-                client: Box::new(DotClient::new(socket_addr, hostname)),
-            }
-        } else {
-            info!("Protocol: DNS");
-            Self {
-                client: Box::new(DnsClient::new(addr)),
-            }
-        }
+        Ok(http_request
+            .header(reqwest::header::ACCEPT, DOH_CONTENT_TYPE)
+            .send()?)
     }
+}
 
-    pub fn query(&mut self, request: dns::DnsPacket) -> dns::DnsPacket {
+impl DnsDest for DohClient {
+    fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
         let mut request = request;
+        let request_id = request.header.id;
+        request.header.id = 0;
 
-        let previous_id = request.header.id;
-        request.header.id = request.header.id.wrapping_add(1);
-        info!(
-            "Proxy query SEND: {} --> {}",
-            previous_id, request.header.id
-        );
-        let mut response = self.client.query(request);
+        let https_response =
+            check_https_response(self.send(request.bytes())?, &[DOH_CONTENT_TYPE])?;
 
-        if response.header.isrequest() {
-            // TODO: handle error
-            panic!();
+        let mut response = parse_response(&https_response.bytes()?)?;
+        if response.header.id == 0 {
+            response.header.id = request_id;
         }
+        Ok(response)
+    }
+}
 
-        let previous_id = response.header.id;
-        response.header.id = response.header.id.wrapping_sub(1);
-        info!(
-            "Proxy query RECV: {} <-- {}",
-            response.header.id, previous_id
+/// An answer from a JSON DNS API, of which only the fields needed to rebuild the DNS response
+/// are kept.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JsonResponse {
+    status: u16,
+    #[serde(rename = "TC", default)]
+    truncated: bool,
+    #[serde(default)]
+    answer: Vec<JsonRecord>,
+    #[serde(default)]
+    authority: Vec<JsonRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRecord {
+    name: String,
+    #[serde(rename = "type")]
+    atype: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    /// The record data in presentation format
+    data: String,
+}
+
+impl JsonRecord {
+    fn to_answer(&self) -> Result<dns::DnsAnswerSection, DestError> {
+        let name = self
+            .name
+            .parse()
+            .map_err(|error| DestError::Json(format!("Bad name `{}`: {error}", self.name)))?;
+        let rdata = dns::RData::from_text(self.atype, &self.data).map_err(DestError::Json)?;
+
+        Ok(dns::DnsAnswerSection {
+            name,
+            atype: self.atype,
+            class: dns::CLASS_IN,
+            ttl: self.ttl,
+            rdata,
+        })
+    }
+
+    /// Leaves out the records that can't be read, such as RRSIGs in presentation form rather
+    /// than the generic `\#` one, instead of failing the whole answer.
+    fn to_answers(records: &[JsonRecord]) -> Vec<dns::DnsAnswerSection> {
+        records
+            .iter()
+            .filter_map(|record| match record.to_answer() {
+                Ok(answer) => Some(answer),
+                Err(error) => {
+                    debug!(
+                        "Skipping JSON record `{}` type {}: {error}",
+                        record.name, record.atype
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Sends queries to the JSON API offered by some DoH providers, eg.
+/// `GET /resolve?name=example.com&type=1`, translating the answer back into a DNS response.
+struct DohJsonClient {
+    https: HttpsClient,
+}
+
+impl DohJsonClient {
+    fn new(url: &str, addrs: &[IpAddr], options: &DestOptions) -> Self {
+        Self {
+            https: HttpsClient::new(url, addrs, options),
+        }
+    }
+}
+
+impl DnsDest for DohJsonClient {
+    fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+        // The API takes a single question
+        let question = match &request.question_section[..] {
+            [question] => question,
+            _ => return Ok(dns::DnsPacket::new_response(&request, dns::RCODE_FORMERR)),
+        };
+
+        let mut url = self.https.url.clone();
+        url.query_pairs_mut()
+            .append_pair("name", &question.qname.to_string())
+            .append_pair("type", &question.qtype.to_string());
+        if request.edns.as_ref().is_some_and(|edns| edns.dnssec_ok) {
+            url.query_pairs_mut().append_pair("do", "1");
+        }
+
+        let https_response = self
+            .https
+            .client()?
+            .get(url)
+            .header(reqwest::header::ACCEPT, DOH_JSON_CONTENT_TYPES[0])
+            .send()?;
+        let https_response = check_https_response(https_response, DOH_JSON_CONTENT_TYPES)?;
+        let json_response: JsonResponse = serde_json::from_slice(&https_response.bytes()?)
+            .map_err(|error| DestError::Json(error.to_string()))?;
+
+        // Extended rcodes keep their upper bits in the OPT record (RFC 6891 6.1.3), so can only
+        // be passed on when the request has one
+        let status = json_response.status;
+        let extended_rcode = u8::try_from(status >> 4).ok();
+        let mut response = match (extended_rcode, request.edns.as_ref()) {
+            (Some(0), _) => dns::DnsPacket::new_response(&request, status),
+            (Some(extended_rcode), Some(request_edns)) => {
+                let mut response = dns::DnsPacket::new_response(&request, status);
+                let mut edns = dns::Edns::new(request_edns.dnssec_ok);
+                edns.extended_rcode = extended_rcode;
+                response.edns = Some(edns);
+                response
+            }
+            _ => dns::DnsPacket::new_response(&request, dns::RCODE_SERVFAIL),
+        };
+        response.header.set_truncated(json_response.truncated);
+        response.add_to_answer_section(&JsonRecord::to_answers(&json_response.answer));
+        response.authority_section = JsonRecord::to_answers(&json_response.authority);
+        response.header.nscount = response.authority_section.len() as u16;
+
+        Ok(response)
+    }
+}
+
+/// Writes each request to `writer` and reads the answer back from `reader`, one exchange at a
+/// time. Normally stdout and stdin.
+struct StdioClient<R, W> {
+    format: StdioFormat,
+    streams: Mutex<(R, W)>,
+}
+
+impl StdioClient<io::BufReader<io::Stdin>, io::Stdout> {
+    fn new(format: StdioFormat) -> Self {
+        Self::with_streams(format, io::BufReader::new(io::stdin()), io::stdout())
+    }
+}
+
+impl<R, W> StdioClient<R, W> {
+    fn with_streams(format: StdioFormat, reader: R, writer: W) -> Self {
+        Self {
+            format,
+            streams: Mutex::new((reader, writer)),
+        }
+    }
+}
+
+impl<R: io::BufRead + Send, W: io::Write + Send> DnsDest for StdioClient<R, W> {
+    fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+        // Held for the whole exchange so concurrent queries do not interleave
+        let mut streams = self.streams.lock().unwrap();
+        let (reader, writer) = &mut *streams;
+
+        self.format.write_message(writer, &request)?;
+
+        if !self.format.is_readable() {
+            return Ok(dns::DnsPacket::new_response(&request, dns::RCODE_SERVFAIL));
+        }
+
+        let response_payload = self.format.read_message(reader)?;
+        parse_response(&response_payload)
+    }
+}
+
+fn new_dest(
+    dest_spec: &DestSpec,
+    options: &DestOptions,
+    format: StdioFormat,
+) -> Box<dyn DnsDest + Send + Sync> {
+    match dest_spec {
+        DestSpec::Stdio => {
+            info!("Protocol: stdio ({format} format)");
+            Box::new(StdioClient::new(format))
+        }
+        DestSpec::Udp(addr) => {
+            info!("Protocol: DNS ({addr})");
+            Box::new(DnsClient::new(*addr, options.timeout, false))
+        }
+        DestSpec::Tcp(addr) => {
+            info!("Protocol: DNS over TCP ({addr})");
+            Box::new(DnsClient::new(*addr, options.timeout, true))
+        }
+        DestSpec::Tls { addr, hostname } => {
+            info!("Protocol: DoT ({addr}#{hostname})");
+            Box::new(DotClient::new(*addr, hostname, options))
+        }
+        DestSpec::Https { url, addrs } => {
+            info!("Protocol: DoH ({url})");
+            Box::new(DohClient::new(url, addrs, options))
+        }
+        DestSpec::Json { url, addrs } => {
+            info!("Protocol: DoH JSON ({url})");
+            Box::new(DohJsonClient::new(url, addrs, options))
+        }
+    }
+}
+
+struct Upstream {
+    addr: String,
+    weight: u32,
+    retries: usize,
+    client: Box<dyn DnsDest + Send + Sync>,
+    is_up: AtomicBool,
+    srtt: Mutex<Option<Duration>>,
+}
+
+impl Upstream {
+    fn new(
+        addr: &str,
+        weight: u32,
+        retries: usize,
+        client: Box<dyn DnsDest + Send + Sync>,
+    ) -> Self {
+        Self {
+            addr: addr.to_string(),
+            weight,
+            retries,
+            client,
+            is_up: AtomicBool::new(true),
+            srtt: Mutex::new(None),
+        }
+    }
+
+    /// Queries the upstream, retrying with exponential backoff when an attempt fails. Good
+    /// answers are timed, and the upstream is marked down once every attempt has failed.
+    fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+        let backoff = Exponential::from_millis(RETRY_BACKOFF_MILLIS).take(self.retries);
+        let result = retry(backoff, || {
+            let start_time = Instant::now();
+            let result = self.client.query(request.clone()).and_then(|response| {
+                match response.is_response_to(&request) {
+                    true => Ok(response),
+                    false => Err(DestError::Mismatched),
+                }
+            });
+            match &result {
+                Ok(response) if response.header.rcode() != dns::RCODE_SERVFAIL => {
+                    self.update_srtt(start_time.elapsed())
+                }
+                Ok(_) => self.penalize_srtt(),
+                Err(error) => {
+                    debug!("Failed attempt at upstream {}: {error}", self.addr);
+                    self.penalize_srtt();
+                }
+            }
+            result
+        });
+
+        result.map_err(|error| {
+            error!(
+                "Failed to query upstream {} (tried {} times): {}",
+                self.addr, error.tries, error.error
+            );
+            self.mark_down(&error.error);
+            error.error
+        })
+    }
+
+    fn update_srtt(&self, rtt: Duration) {
+        let mut srtt = self.srtt.lock().unwrap();
+        let updated_srtt = match *srtt {
+            Some(srtt) => srtt - srtt / SRTT_SAMPLE_DIVISOR + rtt / SRTT_SAMPLE_DIVISOR,
+            None => rtt,
+        };
+
+        debug!("Upstream SRTT: {} {:?}", self.addr, updated_srtt);
+        *srtt = Some(updated_srtt);
+    }
+
+    /// Failures return early, so their RTT says little about the upstream. They make it slower
+    /// instead, so the fastest strategy moves on to another one.
+    fn penalize_srtt(&self) {
+        let mut srtt = self.srtt.lock().unwrap();
+        let updated_srtt = match *srtt {
+            Some(srtt) => (srtt * 2).min(SRTT_MAX),
+            None => SRTT_FAILURE_PENALTY,
+        };
+
+        debug!("Upstream SRTT: {} {:?}", self.addr, updated_srtt);
+        *srtt = Some(updated_srtt);
+    }
+
+    /// Upstreams without samples yet sort first, so every upstream gets timed.
+    fn srtt(&self) -> Duration {
+        self.srtt.lock().unwrap().unwrap_or_default()
+    }
+
+    fn is_up(&self) -> bool {
+        self.is_up.load(Ordering::Relaxed)
+    }
+
+    fn mark_down(&self, error: &DestError) {
+        if self.is_up.swap(false, Ordering::Relaxed) {
+            warn!("Upstream DOWN: {} ({error})", self.addr);
+        }
+    }
+
+    /// Sends a root NS query to an upstream marked down, and marks it up again if it answers.
+    fn probe(&self) {
+        let question = dns::DnsQuestionSection {
+            qname: dns::DnsName::root(),
+            qtype: dns::TYPE_NS,
+            qclass: dns::CLASS_IN,
+        };
+        let request = dns::DnsPacket::new_with_questions(vec![question]);
+
+        match self.client.query(request) {
+            Ok(response) if response.header.rcode() != dns::RCODE_SERVFAIL => {
+                info!("Upstream UP: {}", self.addr);
+                self.is_up.store(true, Ordering::Relaxed);
+            }
+            Ok(_) => debug!("Upstream still failing: {} (SERVFAIL)", self.addr),
+            Err(error) => debug!("Upstream still failing: {} ({error})", self.addr),
+        }
+    }
+}
+
+pub struct DestClient {
+    /// The default upstreams, followed by those only used by forward rules
+    upstreams: Arc<Vec<Upstream>>,
+    default_upstreams: usize,
+    /// Longest suffix first, so the most specific rule matches
+    forwards: Vec<Forward>,
+    strategy: Strategy,
+    next_upstream: AtomicUsize,
+    /// Client IDs of the queries in flight, by the random ID they were sent upstream with
+    client_ids: Mutex<HashMap<u16, u16>>,
+    race_pool: Arc<WorkerPool>,
+
+    _probe_thread: thread::JoinHandle<()>,
+}
+
+/// Queries for `suffix` and the names below it go to the upstream at `upstream_index` alone.
+struct Forward {
+    suffix: dns::DnsName,
+    upstream_index: usize,
+}
+
+impl DestClient {
+    /// `weights` gives one weight per address, used by the random strategy.
+    pub fn new(
+        dest_specs: &[DestSpec],
+        weights: &[u32],
+        forward_rules: &[ForwardRule],
+        strategy: Strategy,
+        options: DestOptions,
+        format: StdioFormat,
+    ) -> Self {
+        if weights.len() != dest_specs.len() {
+            panic!(
+                "Got {} weight(s) for {} destination(s)",
+                weights.len(),
+                dest_specs.len()
+            );
+        }
+
+        info!("Strategy: {strategy}");
+        let mut upstreams: Vec<Upstream> = dest_specs
+            .iter()
+            .zip(weights)
+            .map(|(dest_spec, weight)| {
+                let client = new_dest(dest_spec, &options, format);
+                Upstream::new(&dest_spec.to_string(), *weight, options.retries, client)
+            })
+            .collect();
+        let default_upstreams = upstreams.len();
+
+        // Rules with the same destination share its upstream, and so its connections
+        let mut forward_specs: Vec<&DestSpec> = Vec::new();
+        let mut forwards: Vec<Forward> = Vec::new();
+        for rule in forward_rules {
+            info!("Forwarding: {rule}");
+            let upstream_index = match forward_specs.iter().position(|spec| **spec == rule.dest) {
+                Some(position) => default_upstreams + position,
+                None => {
+                    let client = new_dest(&rule.dest, &options, format);
+                    upstreams.push(Upstream::new(
+                        &rule.dest.to_string(),
+                        1,
+                        options.retries,
+                        client,
+                    ));
+                    forward_specs.push(&rule.dest);
+                    upstreams.len() - 1
+                }
+            };
+            forwards.push(Forward {
+                suffix: rule.suffix.clone(),
+                upstream_index,
+            });
+        }
+
+        Self::with_forwards(upstreams, default_upstreams, forwards, strategy)
+    }
+
+    /// A client with `client` as its only upstream, for testing the modules built on it.
+    #[cfg(test)]
+    pub(crate) fn with_dest(client: Box<dyn DnsDest + Send + Sync>) -> Self {
+        Self::with_upstreams(
+            vec![Upstream::new("mock", 1, 0, client)],
+            Strategy::Failover,
+        )
+    }
+
+    #[cfg(test)]
+    fn with_upstreams(upstreams: Vec<Upstream>, strategy: Strategy) -> Self {
+        let default_upstreams = upstreams.len();
+        Self::with_forwards(upstreams, default_upstreams, vec![], strategy)
+    }
+
+    /// The first `default_upstreams` of `upstreams` answer the queries no forward matches.
+    fn with_forwards(
+        upstreams: Vec<Upstream>,
+        default_upstreams: usize,
+        forwards: Vec<Forward>,
+        strategy: Strategy,
+    ) -> Self {
+        let upstreams = Arc::new(upstreams);
+        let mut forwards = forwards;
+        // Stable, so the first of several rules for the same suffix wins
+        forwards.sort_by_key(|forward| std::cmp::Reverse(forward.suffix.label_count()));
+
+        let probe_thread = {
+            let upstreams = Arc::clone(&upstreams);
+
+            thread::spawn(move || loop {
+                thread::sleep(PROBE_INTERVAL);
+                for upstream in upstreams.iter() {
+                    if !upstream.is_up() {
+                        upstream.probe();
+                    }
+                }
+            })
+        };
+
+        Self {
+            upstreams,
+            default_upstreams,
+            forwards,
+            strategy,
+            next_upstream: AtomicUsize::new(0),
+            client_ids: Mutex::new(HashMap::new()),
+            race_pool: WorkerPool::new(RACE_MAX_THREADS, RACE_MAX_QUEUED),
+            _probe_thread: probe_thread,
+        }
+    }
+
+    /// Picks a random ID to send a query upstream with, unused by the other queries in flight,
+    /// so upstream IDs can't be predicted from client IDs (RFC 5452 9.2).
+    fn map_id(&self, client_id: u16) -> u16 {
+        let mut client_ids = self.client_ids.lock().unwrap();
+        let mut upstream_id: u16 = random();
+        while client_ids.contains_key(&upstream_id) {
+            upstream_id = random();
+        }
+        client_ids.insert(upstream_id, client_id);
+
+        upstream_id
+    }
+
+    /// Releases an upstream ID, giving back the client ID it was mapped from.
+    fn unmap_id(&self, upstream_id: u16) -> u16 {
+        self.client_ids
+            .lock()
+            .unwrap()
+            .remove(&upstream_id)
+            .expect("Upstream ID is mapped until the query finishes")
+    }
+
+    /// The upstream of the longest forward suffix matching the question name, if any.
+    fn forwarded_upstream(&self, request: &dns::DnsPacket) -> Option<usize> {
+        let qname = &request.question_section.first()?.qname;
+        let forward = self
+            .forwards
+            .iter()
+            .find(|forward| qname.is_subdomain_of(&forward.suffix))?;
+
+        debug!(
+            "Forwarding `{qname}` to {}",
+            self.upstreams[forward.upstream_index].addr
+        );
+        Some(forward.upstream_index)
+    }
+
+    /// Indices of the default upstreams to try, in the order given by the strategy. Only
+    /// upstreams that are up are included, unless every upstream is down, in which case they
+    /// are all tried.
+    fn candidates(&self) -> Vec<usize> {
+        let mut candidates: Vec<usize> = (0..self.default_upstreams)
+            .filter(|index| self.upstreams[*index].is_up())
+            .collect();
+        if candidates.is_empty() {
+            candidates = (0..self.default_upstreams).collect();
+        }
+
+        let mut rng = thread_rng();
+        match self.strategy {
+            Strategy::Failover | Strategy::Race => {}
+            Strategy::RoundRobin => {
+                let start = self.next_upstream.fetch_add(1, Ordering::Relaxed) % candidates.len();
+                candidates.rotate_left(start);
+            }
+            Strategy::Random => {
+                let mut remaining = candidates;
+                candidates = Vec::with_capacity(remaining.len());
+                while !remaining.is_empty() {
+                    let weights = remaining.iter().map(|index| self.upstreams[*index].weight);
+                    let position = WeightedIndex::new(weights)
+                        .map(|distribution| distribution.sample(&mut rng))
+                        .unwrap_or_default();
+                    candidates.push(remaining.remove(position));
+                }
+            }
+            Strategy::Fastest => {
+                candidates.sort_by_key(|index| self.upstreams[*index].srtt());
+                if candidates.len() > 1 && rng.gen_bool(EXPLORE_PROBABILITY) {
+                    let explored = candidates.remove(rng.gen_range(1..candidates.len()));
+                    candidates.insert(0, explored);
+                }
+            }
+        }
+
+        candidates
+    }
+
+    /// Tries the candidates one at a time until one answers without SERVFAIL.
+    fn query_in_order(&self, request: &dns::DnsPacket, candidates: &[usize]) -> dns::DnsPacket {
+        let mut last_response: Option<dns::DnsPacket> = None;
+        for index in candidates {
+            let upstream = &self.upstreams[*index];
+            match upstream.query(request.clone()) {
+                Ok(response) if response.header.rcode() == dns::RCODE_SERVFAIL => {
+                    debug!("Upstream answered SERVFAIL: {}", upstream.addr);
+                    last_response = Some(response);
+                }
+                Ok(response) => return response,
+                Err(_) => {}
+            }
+        }
+
+        last_response.unwrap_or_else(|| dns::DnsPacket::new_response(request, dns::RCODE_SERVFAIL))
+    }
+
+    /// Queries all candidates at once and returns the first answer without SERVFAIL. The slower
+    /// queries still finish in the background, so their RTTs are recorded.
+    fn query_race(&self, request: &dns::DnsPacket, candidates: &[usize]) -> dns::DnsPacket {
+        let (result_sender, result_receiver) = mpsc::channel();
+        for index in candidates {
+            let index = *index;
+            let upstreams = Arc::clone(&self.upstreams);
+            let request = request.clone();
+            let result_sender = result_sender.clone();
+
+            self.race_pool.execute(move || {
+                let result = upstreams[index].query(request);
+                let _ = result_sender.send((index, result));
+            });
+        }
+        drop(result_sender);
+
+        let mut last_response: Option<dns::DnsPacket> = None;
+        for (index, result) in result_receiver {
+            match result {
+                Ok(response) if response.header.rcode() == dns::RCODE_SERVFAIL => {
+                    debug!("Upstream answered SERVFAIL: {}", self.upstreams[index].addr);
+                    last_response = Some(response);
+                }
+                Ok(response) => {
+                    debug!("Upstream won race: {}", self.upstreams[index].addr);
+                    return response;
+                }
+                Err(_) => {}
+            }
+        }
+
+        last_response.unwrap_or_else(|| dns::DnsPacket::new_response(request, dns::RCODE_SERVFAIL))
+    }
+
+    pub fn query(&self, request: dns::DnsPacket) -> dns::DnsPacket {
+        let mut request = request;
+
+        // OPT records are hop-by-hop (RFC 6891 6.1.1), so upstreams always get our own payload
+        // size and only the client's DO bit is passed through
+        let client_edns = request.edns.take();
+        let dnssec_ok = client_edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+        request.edns = Some(dns::Edns::new(dnssec_ok));
+
+        let upstream_id = self.map_id(request.header.id);
+        info!(
+            "Proxy query SEND: {} --> {}",
+            request.header.id, upstream_id
+        );
+        request.header.id = upstream_id;
+
+        let mut response = match (self.forwarded_upstream(&request), self.strategy) {
+            (Some(index), _) => self.query_in_order(&request, &[index]),
+            (None, Strategy::Race) => self.query_race(&request, &self.candidates()),
+            (None, _) => self.query_in_order(&request, &self.candidates()),
+        };
+
+        let extended_rcode = response.edns.map_or(0, |edns| edns.extended_rcode);
+        response.edns = client_edns.map(|client_edns| {
+            let mut edns = dns::Edns::new(client_edns.dnssec_ok);
+            edns.extended_rcode = extended_rcode;
+            edns
+        });
+        // Clients without EDNS can't be told an extended rcode
+        if response.edns.is_none() && extended_rcode != 0 {
+            response.header.set_rcode(dns::RCODE_SERVFAIL);
+        }
+
+        response.header.id = self.unmap_id(upstream_id);
+        info!(
+            "Proxy query RECV: {} <-- {}",
+            response.header.id, upstream_id
         );
 
         response
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every query with `rcode` after `delay`, or times out when `rcode` is `None`.
+    struct MockDest {
+        rcode: Option<u16>,
+        delay: Duration,
+    }
+
+    impl DnsDest for MockDest {
+        fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+            thread::sleep(self.delay);
+            match self.rcode {
+                Some(rcode) => Ok(dns::DnsPacket::new_response(&request, rcode)),
+                None => Err(DestError::Timeout),
+            }
+        }
+    }
+
+    /// Times out on its first `failures` attempts, then answers NOERROR.
+    struct FlakyDest {
+        failures: usize,
+        attempts: AtomicUsize,
+    }
+
+    impl DnsDest for FlakyDest {
+        fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+            if self.attempts.fetch_add(1, Ordering::Relaxed) < self.failures {
+                return Err(DestError::Timeout);
+            }
+            Ok(dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR))
+        }
+    }
+
+    fn mock_upstream(addr: &str, rcode: Option<u16>, delay_millis: u64) -> Upstream {
+        let client = MockDest {
+            rcode,
+            delay: Duration::from_millis(delay_millis),
+        };
+        Upstream::new(addr, 1, 1, Box::new(client))
+    }
+
+    /// No retries, GET over HTTP/1.1 for DoH, and TLS that trusts only the `localhost` test
+    /// certificate.
+    fn test_options() -> DestOptions {
+        DestOptions {
+            timeout: Duration::from_secs(2),
+            retries: 0,
+            keepalive: false,
+            tls_config: Arc::new(crate::tls::testing::client_config()),
+            doh_method: DohMethod::Get,
+            doh_http_version: HttpVersion::Http1,
+            bootstrap: None,
+        }
+    }
+
+    fn request() -> dns::DnsPacket {
+        let question = dns::DnsQuestionSection {
+            qname: "www.example.com".parse().unwrap(),
+            qtype: dns::TYPE_A,
+            qclass: dns::CLASS_IN,
+        };
+        dns::DnsPacket::new_with_questions(vec![question])
+    }
+
+    #[test]
+    fn strategy_parse_display() {
+        for strategy in ["failover", "round-robin", "random", "fastest", "race"] {
+            assert_eq!(Strategy::from_str(strategy).unwrap().to_string(), strategy);
+        }
+        assert!(Strategy::from_str("fastest-first").is_err());
+    }
+
+    #[test]
+    fn dest_spec_parse() {
+        let valid = [
+            ("-", "-"),
+            ("1.1.1.1", "udp://1.1.1.1:53"),
+            ("udp://1.1.1.1:5353", "udp://1.1.1.1:5353"),
+            ("2606:4700::1111", "udp://[2606:4700::1111]:53"),
+            ("tcp://[2606:4700::1111]", "tcp://[2606:4700::1111]:53"),
+            ("8.8.8.8#dns.google", "tls://8.8.8.8:853#dns.google"),
+            (
+                "tls://[2606:4700::1111]:853#one.one.one.one",
+                "tls://[2606:4700::1111]:853#one.one.one.one",
+            ),
+            ("tls://1.1.1.1:8853", "tls://1.1.1.1:8853#1.1.1.1"),
+            (
+                "https://cloudflare-dns.com/dns-query",
+                "https://cloudflare-dns.com/dns-query",
+            ),
+            (
+                "https://cloudflare-dns.com/dns-query@1.1.1.1,2606:4700::1111",
+                "https://cloudflare-dns.com/dns-query@1.1.1.1,2606:4700::1111",
+            ),
+            (
+                "json+https://dns.google/resolve@8.8.8.8",
+                "json+https://dns.google/resolve@8.8.8.8",
+            ),
+            (
+                "https://u@doh.test/dns-query?x=a@b",
+                "https://u@doh.test/dns-query?x=a@b",
+            ),
+            ("https://u@10.0.0.1", "https://u@10.0.0.1"),
+            (
+                "https://u@doh.test/dns-query@10.0.0.1",
+                "https://u@doh.test/dns-query@10.0.0.1",
+            ),
+        ];
+        for (dest, canonical) in valid {
+            let dest_spec = DestSpec::from_str(dest).unwrap();
+            assert_eq!(dest_spec.to_string(), canonical);
+            assert_eq!(DestSpec::from_str(canonical), Ok(dest_spec));
+        }
+
+        // Only the suffix after the path gives addresses
+        let addrs_of = |dest: &str| match DestSpec::from_str(dest).unwrap() {
+            DestSpec::Https { url, addrs } => (url, addrs),
+            dest_spec => panic!("`{dest_spec}` is not HTTPS"),
+        };
+        assert_eq!(
+            addrs_of("https://u@doh.test/dns-query?x=a@b"),
+            ("https://u@doh.test/dns-query?x=a@b".to_string(), vec![])
+        );
+        assert_eq!(
+            addrs_of("https://u@doh.test/dns-query@10.0.0.1"),
+            (
+                "https://u@doh.test/dns-query".to_string(),
+                vec![IpAddr::from([10, 0, 0, 1])]
+            )
+        );
+
+        let invalid = [
+            "",
+            "dns.google",
+            "1.1.1.1:99999",
+            "udp://[::1",
+            "tls://1.1.1.1#",
+            "tls://1.1.1.1#bad_name!",
+            "quic://1.1.1.1",
+            "https://",
+            "https://cloudflare-dns.com/dns-query@1.1.1",
+            "json+https://",
+        ];
+        for dest in invalid {
+            assert!(DestSpec::from_str(dest).is_err(), "{dest}");
+        }
+    }
+
+    #[test]
+    fn forward_rule_parse() {
+        let rule = ForwardRule::from_str("*.corp.internal=10.0.0.53").unwrap();
+        assert_eq!(rule.suffix, "corp.internal".parse().unwrap());
+        assert_eq!(rule.to_string(), "corp.internal.=udp://10.0.0.53:53");
+        assert_eq!(ForwardRule::from_str(&rule.to_string()), Ok(rule));
+
+        for rule in [
+            "corp.internal",
+            "corp..internal=10.0.0.53",
+            "lan=quic://1.1.1.1",
+        ] {
+            assert!(ForwardRule::from_str(rule).is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn forward_longest_suffix_wins() {
+        const RCODE_NXDOMAIN: u16 = 3;
+        const RCODE_REFUSED: u16 = 5;
+        let forward = |suffix: &str, upstream_index| Forward {
+            suffix: suffix.parse().unwrap(),
+            upstream_index,
+        };
+        let dest_client = DestClient::with_forwards(
+            vec![
+                mock_upstream("default", Some(dns::RCODE_NOERROR), 0),
+                mock_upstream("corp", Some(RCODE_NXDOMAIN), 0),
+                mock_upstream("dev.corp", Some(RCODE_REFUSED), 0),
+            ],
+            1,
+            vec![forward("corp.internal", 1), forward("dev.corp.internal", 2)],
+            Strategy::Race,
+        );
+
+        for (qname, rcode) in [
+            ("www.example.com", dns::RCODE_NOERROR),
+            ("notcorp.internal", dns::RCODE_NOERROR),
+            ("corp.internal", RCODE_NXDOMAIN),
+            ("WWW.Corp.Internal", RCODE_NXDOMAIN),
+            ("a.dev.corp.internal", RCODE_REFUSED),
+        ] {
+            let mut request = request();
+            request.question_section[0].qname = qname.parse().unwrap();
+            assert_eq!(dest_client.query(request).header.rcode(), rcode, "{qname}");
+        }
+
+        // Forwarding upstreams are never picked for other queries
+        assert_eq!(dest_client.candidates(), vec![0]);
+    }
+
+    #[test]
+    fn stdio_writes_request_and_reads_answer() {
+        let request = request();
+        let answer = dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR);
+        let mut input: Vec<u8> = Vec::new();
+        StdioFormat::Wire
+            .write_message(&mut input, &answer)
+            .unwrap();
+
+        let client = StdioClient::with_streams(StdioFormat::Wire, io::Cursor::new(input), vec![]);
+        assert_eq!(client.query(request.clone()).unwrap(), answer);
+
+        let (_, output) = &*client.streams.lock().unwrap();
+        let written = framing::read_length_prefixed(&mut &output[..]).unwrap();
+        assert_eq!(dns::DnsPacket::from_slice(&written).unwrap(), request);
+    }
+
+    #[test]
+    fn stdio_eof_or_garbage_answers_servfail() {
+        let mut garbage: Vec<u8> = Vec::new();
+        framing::write_length_prefixed(&mut garbage, b"\x2b\x25\x81").unwrap();
+
+        for input in [vec![], garbage] {
+            let client =
+                StdioClient::with_streams(StdioFormat::Wire, io::Cursor::new(input), vec![]);
+            let dest_client = DestClient::with_upstreams(
+                vec![Upstream::new(STDIO_ADDR, 1, 0, Box::new(client))],
+                Strategy::Failover,
+            );
+
+            let response = dest_client.query(request());
+            assert_eq!(response.header.rcode(), dns::RCODE_SERVFAIL);
+        }
+    }
+
+    #[test]
+    fn failover_marks_failing_upstream_down() {
+        let dest_client = DestClient::with_upstreams(
+            vec![
+                mock_upstream("timeout", None, 0),
+                mock_upstream("servfail", Some(dns::RCODE_SERVFAIL), 0),
+                mock_upstream("noerror", Some(dns::RCODE_NOERROR), 0),
+            ],
+            Strategy::Failover,
+        );
+
+        let response = dest_client.query(request());
+        assert_eq!(response.header.rcode(), dns::RCODE_NOERROR);
+
+        // SERVFAIL moves on to the next upstream without marking it down
+        assert_eq!(dest_client.candidates(), vec![1, 2]);
+    }
+
+    #[test]
+    fn all_upstreams_failing_answers_servfail() {
+        let dest_client =
+            DestClient::with_upstreams(vec![mock_upstream("timeout", None, 0)], Strategy::Failover);
+
+        let request = request();
+        let response = dest_client.query(request.clone());
+        assert_eq!(response.header.rcode(), dns::RCODE_SERVFAIL);
+        assert_eq!(response.header.id, request.header.id);
+        assert_eq!(dest_client.candidates(), vec![0]);
+    }
+
+    #[test]
+    fn round_robin_rotates_start() {
+        let dest_client = DestClient::with_upstreams(
+            vec![
+                mock_upstream("a", Some(dns::RCODE_NOERROR), 0),
+                mock_upstream("b", Some(dns::RCODE_NOERROR), 0),
+                mock_upstream("c", Some(dns::RCODE_NOERROR), 0),
+            ],
+            Strategy::RoundRobin,
+        );
+
+        assert_eq!(dest_client.candidates(), vec![0, 1, 2]);
+        assert_eq!(dest_client.candidates(), vec![1, 2, 0]);
+        assert_eq!(dest_client.candidates(), vec![2, 0, 1]);
+        assert_eq!(dest_client.candidates(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn fastest_sorts_by_srtt_and_sometimes_explores() {
+        let dest_client = DestClient::with_upstreams(
+            vec![
+                mock_upstream("a", Some(dns::RCODE_NOERROR), 0),
+                mock_upstream("b", Some(dns::RCODE_NOERROR), 0),
+                mock_upstream("c", Some(dns::RCODE_NOERROR), 0),
+            ],
+            Strategy::Fastest,
+        );
+        dest_client.upstreams[0].update_srtt(Duration::from_millis(50));
+        dest_client.upstreams[1].update_srtt(Duration::from_millis(100));
+        dest_client.upstreams[2].update_srtt(Duration::from_millis(10));
+
+        const DRAWS: usize = 2000;
+        let mut explored = 0;
+        for _ in 0..DRAWS {
+            match dest_client.candidates()[..] {
+                [2, 0, 1] => {}
+                // One of the slower upstreams goes first, and the rest keep their order
+                [0, 2, 1] | [1, 2, 0] => explored += 1,
+                ref candidates => panic!("Unexpected order {candidates:?}"),
+            }
+        }
+        // About EXPLORE_PROBABILITY of the draws
+        assert!(explored > 0 && explored < DRAWS / 5, "explored {explored}");
+    }
+
+    #[test]
+    fn random_orders_by_weight() {
+        let upstream = |addr, weight| {
+            let client = MockDest {
+                rcode: Some(dns::RCODE_NOERROR),
+                delay: Duration::ZERO,
+            };
+            Upstream::new(addr, weight, 1, Box::new(client))
+        };
+        let dest_client = DestClient::with_upstreams(
+            vec![upstream("light", 1), upstream("heavy", 1000)],
+            Strategy::Random,
+        );
+
+        const DRAWS: usize = 1000;
+        let mut heavy_first = 0;
+        for _ in 0..DRAWS {
+            let mut candidates = dest_client.candidates();
+            if candidates[0] == 1 {
+                heavy_first += 1;
+            }
+            candidates.sort();
+            assert_eq!(candidates, vec![0, 1]);
+        }
+        assert!(heavy_first >= DRAWS * 98 / 100, "heavy first {heavy_first}");
+    }
+
+    #[test]
+    fn srtt_is_smoothed() {
+        let upstream = mock_upstream("a", Some(dns::RCODE_NOERROR), 0);
+        assert_eq!(upstream.srtt(), Duration::ZERO);
+
+        upstream.update_srtt(Duration::from_millis(100));
+        assert_eq!(upstream.srtt(), Duration::from_millis(100));
+
+        upstream.update_srtt(Duration::from_millis(20));
+        assert_eq!(upstream.srtt(), Duration::from_millis(90));
+    }
+
+    #[test]
+    fn srtt_samples_only_good_answers() {
+        let upstream = mock_upstream("servfail", Some(dns::RCODE_SERVFAIL), 0);
+        upstream.query(request()).unwrap();
+        assert_eq!(upstream.srtt(), SRTT_FAILURE_PENALTY);
+        upstream.query(request()).unwrap();
+        assert_eq!(upstream.srtt(), SRTT_FAILURE_PENALTY * 2);
+
+        // Both the attempt and its retry count
+        let upstream = mock_upstream("timeout", None, 0);
+        assert!(upstream.query(request()).is_err());
+        assert_eq!(upstream.srtt(), SRTT_FAILURE_PENALTY * 2);
+        for _ in 0..10 {
+            upstream.penalize_srtt();
+        }
+        assert_eq!(upstream.srtt(), SRTT_MAX);
+
+        let upstream = mock_upstream("good", Some(dns::RCODE_NOERROR), 0);
+        upstream.query(request()).unwrap();
+        let srtt = upstream.srtt();
+        assert!(srtt < SRTT_FAILURE_PENALTY);
+        upstream.penalize_srtt();
+        assert_eq!(upstream.srtt(), srtt * 2);
+    }
+
+    #[test]
+    fn race_takes_first_good_answer() {
+        /// Answers NOERROR once the test opens the gate, then reports that it has.
+        struct GatedDest {
+            gate: Mutex<mpsc::Receiver<()>>,
+            answered: mpsc::Sender<()>,
+        }
+
+        impl DnsDest for GatedDest {
+            fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+                let _ = self.gate.lock().unwrap().recv();
+                let _ = self.answered.send(());
+                Ok(dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR))
+            }
+        }
+
+        let (gate_sender, gate_receiver) = mpsc::channel();
+        let (answered_sender, answered_receiver) = mpsc::channel();
+        let gated = GatedDest {
+            gate: Mutex::new(gate_receiver),
+            answered: answered_sender,
+        };
+        let dest_client = DestClient::with_upstreams(
+            vec![
+                Upstream::new("gated", 1, 0, Box::new(gated)),
+                mock_upstream("servfail", Some(dns::RCODE_SERVFAIL), 0),
+                mock_upstream("good", Some(dns::RCODE_NOERROR), 0),
+            ],
+            Strategy::Race,
+        );
+
+        // Returns while the gated upstream is still waiting, so the good one won
+        let response = dest_client.query(request());
+        assert_eq!(response.header.rcode(), dns::RCODE_NOERROR);
+        assert!(answered_receiver.try_recv().is_err());
+
+        // The loser still finishes in the background and gets timed
+        gate_sender.send(()).unwrap();
+        answered_receiver.recv().unwrap();
+        while dest_client.upstreams[0].srtt.lock().unwrap().is_none() {
+            thread::yield_now();
+        }
+        assert!(dest_client.upstreams[0].srtt() < SRTT_FAILURE_PENALTY);
+    }
+
+    #[test]
+    fn retries_before_marking_down() {
+        let flaky = |failures| FlakyDest {
+            failures,
+            attempts: AtomicUsize::new(0),
+        };
+
+        // One retry covers a single failed attempt
+        let upstream = Upstream::new("flaky", 1, 1, Box::new(flaky(1)));
+        assert!(upstream.query(request()).is_ok());
+        assert!(upstream.is_up());
+
+        // The upstream is marked down once every attempt has failed
+        let upstream = Upstream::new("flaky", 1, 1, Box::new(flaky(2)));
+        assert!(matches!(upstream.query(request()), Err(DestError::Timeout)));
+        assert!(!upstream.is_up());
+    }
+
+    #[test]
+    fn udp_skips_spoofed_and_mismatched_responses() {
+        let upstream_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let spoofer_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = DnsClient::new(
+            upstream_socket.local_addr().unwrap(),
+            Duration::from_secs(2),
+            false,
+        );
+
+        let upstream = thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (number_of_bytes, client_addr) = upstream_socket.recv_from(&mut buf).unwrap();
+            let request = dns::DnsPacket::from_slice(&buf[..number_of_bytes]).unwrap();
+
+            let forged = dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR);
+            spoofer_socket
+                .send_to(&forged.bytes(), client_addr)
+                .unwrap();
+
+            let mut late = dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR);
+            late.header.id = request.header.id.wrapping_sub(1);
+            upstream_socket.send_to(&late.bytes(), client_addr).unwrap();
+
+            let response = dns::DnsPacket::new_response(&request, dns::RCODE_SERVFAIL);
+            upstream_socket
+                .send_to(&response.bytes(), client_addr)
+                .unwrap();
+        });
+
+        let request = request();
+        let response = client.query(request.clone()).unwrap();
+        assert!(response.is_response_to(&request));
+        assert_eq!(response.header.rcode(), dns::RCODE_SERVFAIL);
+        upstream.join().unwrap();
+    }
+
+    #[test]
+    fn upstream_ids_are_unique_and_map_back() {
+        let dest_client = DestClient::with_upstreams(
+            vec![mock_upstream("noerror", Some(dns::RCODE_NOERROR), 0)],
+            Strategy::Failover,
+        );
+
+        // Two clients picking the same ID still get distinct upstream IDs
+        let first_id = dest_client.map_id(0x2b25);
+        let second_id = dest_client.map_id(0x2b25);
+        assert_ne!(first_id, second_id);
+        assert_eq!(dest_client.unmap_id(first_id), 0x2b25);
+        assert_eq!(dest_client.unmap_id(second_id), 0x2b25);
+
+        let request = request();
+        let response = dest_client.query(request.clone());
+        assert_eq!(response.header.id, request.header.id);
+        assert!(dest_client.client_ids.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn udp_truncated_falls_back_to_tcp() {
+        let upstream_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream_socket.local_addr().unwrap();
+        let upstream_listener = std::net::TcpListener::bind(upstream_addr).unwrap();
+        let client = DnsClient::new(upstream_addr, Duration::from_secs(2), false);
+
+        let upstream = thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (number_of_bytes, client_addr) = upstream_socket.recv_from(&mut buf).unwrap();
+            let request = dns::DnsPacket::from_slice(&buf[..number_of_bytes]).unwrap();
+
+            let mut response = dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR);
+            response.answer_section.push(dns::DnsAnswerSection {
+                name: request.question_section[0].qname.clone(),
+                atype: dns::TYPE_TXT,
+                class: dns::CLASS_IN,
+                ttl: 60,
+                rdata: dns::RData::Other {
+                    data: vec![0x7f; 1024],
+                },
+            });
+            let truncated = response.bytes_truncated(dns::DNS_UDP_MAX_LEN);
+            upstream_socket.send_to(&truncated, client_addr).unwrap();
+
+            let (mut stream, _) = upstream_listener.accept().unwrap();
+            framing::read_length_prefixed(&mut stream).unwrap();
+            framing::write_length_prefixed(&mut stream, &response.bytes()).unwrap();
+        });
+
+        let response = client.query(request()).unwrap();
+        assert!(!response.header.istruncated());
+        assert_eq!(response.answer_section.len(), 1);
+        upstream.join().unwrap();
+    }
+
+    type DotStubStream = rustls::StreamOwned<rustls::ServerConnection, TcpStream>;
+
+    /// Serves DoT on a loopback port with the `localhost` test certificate, handing each
+    /// connection and its index to `serve` on a thread of its own.
+    fn spawn_dot_stub<F>(serve: F) -> SocketAddr
+    where
+        F: Fn(usize, DotStubStream) + Send + Sync + 'static,
+    {
+        spawn_dot_stub_with(crate::tls::testing::server_config(), serve)
+    }
+
+    fn spawn_dot_stub_with<F>(server_config: rustls::ServerConfig, serve: F) -> SocketAddr
+    where
+        F: Fn(usize, DotStubStream) + Send + Sync + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_config = Arc::new(server_config);
+        let serve = Arc::new(serve);
+
+        thread::spawn(move || {
+            for (index, socket) in listener.incoming().enumerate() {
+                let tls_conn = rustls::ServerConnection::new(server_config.clone()).unwrap();
+                let stream = rustls::StreamOwned::new(tls_conn, socket.unwrap());
+                let serve = serve.clone();
+                thread::spawn(move || serve(index, stream));
+            }
+        });
+        addr
+    }
+
+    fn read_dot_request(stream: &mut DotStubStream) -> Option<dns::DnsPacket> {
+        let message = framing::read_length_prefixed(stream).ok()?;
+        Some(dns::DnsPacket::from_slice(&message).unwrap())
+    }
+
+    fn write_dot_response(stream: &mut DotStubStream, response: &dns::DnsPacket) {
+        framing::write_length_prefixed(stream, &response.bytes()).unwrap();
+        io::Write::flush(stream).unwrap();
+    }
+
+    fn dot_request(qname: &str) -> dns::DnsPacket {
+        let question = dns::DnsQuestionSection {
+            qname: qname.parse().unwrap(),
+            qtype: dns::TYPE_A,
+            qclass: dns::CLASS_IN,
+        };
+        let mut request = dns::DnsPacket::new_with_questions(vec![question]);
+        request.edns = Some(dns::Edns::new(false));
+        request
+    }
+
+    #[test]
+    fn dot_matches_responses_out_of_order() {
+        let addr = spawn_dot_stub(|_, mut stream| {
+            let first = read_dot_request(&mut stream).unwrap();
+            let second = read_dot_request(&mut stream).unwrap();
+            for request in [second, first] {
+                let response = dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR);
+                write_dot_response(&mut stream, &response);
+            }
+        });
+        let client = DotClient::new(addr, "localhost", &test_options());
+        let connection = Arc::new(client.connect().unwrap());
+
+        let queries: Vec<_> = ["first.test", "second.test"]
+            .into_iter()
+            .map(|qname| {
+                let connection = connection.clone();
+                let request = dot_request(qname);
+                thread::spawn(move || {
+                    let response = connection.query(&request, Duration::from_secs(5));
+                    (request, response.unwrap())
+                })
+            })
+            .collect();
+
+        for query in queries {
+            let (request, response) = query.join().unwrap();
+            assert!(response.is_response_to(&request));
+        }
+    }
+
+    #[test]
+    fn dot_fails_pending_queries_and_reconnects() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let stub_connections = connections.clone();
+        let addr = spawn_dot_stub(move |index, mut stream| {
+            stub_connections.fetch_add(1, Ordering::Relaxed);
+            // The first connection drops once two queries are waiting on it
+            if index == 0 {
+                read_dot_request(&mut stream).unwrap();
+                read_dot_request(&mut stream).unwrap();
+                return;
+            }
+            while let Some(request) = read_dot_request(&mut stream) {
+                let response = dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR);
+                write_dot_response(&mut stream, &response);
+            }
+        });
+        let client = DotClient::new(addr, "localhost", &test_options());
+
+        let (connection, is_reused) = client.connection().unwrap();
+        assert!(!is_reused);
+        let queries: Vec<_> = (0..2)
+            .map(|_| {
+                let connection = connection.clone();
+                thread::spawn(move || {
+                    connection.query(&dot_request("drop.test"), Duration::from_secs(5))
+                })
+            })
+            .collect();
+        for query in queries {
+            assert!(matches!(query.join().unwrap(), Err(DestError::Io(_))));
+        }
+        assert!(!connection.is_usable());
+
+        // Every slot of the pool answers, the broken one on a new connection
+        for _ in 0..DOT_POOL_SIZE {
+            let request = dot_request("ok.test");
+            assert!(client
+                .query(request.clone())
+                .unwrap()
+                .is_response_to(&request));
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), DOT_POOL_SIZE + 1);
+    }
+
+    #[test]
+    fn dot_checks_ca_pins_and_sends_client_cert() {
+        use crate::tls::{self, testing::LOCALHOST_SPKI_PIN};
+
+        let (peer_sender, peer_receiver) = mpsc::channel();
+        let peer_sender = Mutex::new(peer_sender);
+        let server_config = tls::testing::server_config_with_client_auth();
+        let addr = spawn_dot_stub_with(server_config, move |_, mut stream| {
+            while let Some(request) = read_dot_request(&mut stream) {
+                let peer_certs = stream.conn.peer_certificates().map(<[_]>::to_vec);
+                peer_sender.lock().unwrap().send(peer_certs).unwrap();
+                let response = dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR);
+                write_dot_response(&mut stream, &response);
+            }
+        });
+
+        let (cert_path, key_path) = tls::testing::localhost_pem_files();
+        let ca_paths = [cert_path.clone()];
+        let right_pin = tls::SpkiPin::from_str(LOCALHOST_SPKI_PIN).unwrap();
+        let wrong_pin = tls::SpkiPin::from_str(&format!("{}=", "A".repeat(43))).unwrap();
+        let client_cert = Some((cert_path.as_str(), key_path.as_str()));
+        let query = |ca_paths: &[String], pins: &[tls::SpkiPin], client_cert| {
+            let options = DestOptions {
+                tls_config: tls::client_config(ca_paths, pins, client_cert),
+                ..test_options()
+            };
+            let request = dot_request("auth.test");
+            DotClient::new(addr, "localhost", &options)
+                .query(request.clone())
+                .map(|response| assert!(response.is_response_to(&request)))
+        };
+
+        // The CA from --dot-ca is trusted, and the server gets the client certificate
+        query(&ca_paths, &[], client_cert).unwrap();
+        let peer_certs = peer_receiver.try_recv().unwrap().unwrap();
+        assert_eq!(peer_certs, tls::load_certs(&cert_path));
+        query(&ca_paths, std::slice::from_ref(&right_pin), client_cert).unwrap();
+        query(
+            &ca_paths,
+            &[wrong_pin.clone(), right_pin.clone()],
+            client_cert,
+        )
+        .unwrap();
+
+        assert!(query(&ca_paths, &[wrong_pin], client_cert).is_err());
+        // Pins add to the CA check rather than replace it
+        assert!(query(&[], &[right_pin], client_cert).is_err());
+        assert!(query(&[], &[], client_cert).is_err());
+        // The server requires a client certificate
+        assert!(query(&ca_paths, &[], None).is_err());
+
+        std::fs::remove_file(cert_path).unwrap();
+        std::fs::remove_file(key_path).unwrap();
+    }
+
+    #[test]
+    fn dot_keeps_connections_for_advertised_keepalive() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let (closed_sender, closed_receiver) = mpsc::channel();
+        let stub_connections = connections.clone();
+        let closed_sender = Mutex::new(closed_sender);
+        let addr = spawn_dot_stub(move |index, mut stream| {
+            stub_connections.fetch_add(1, Ordering::Relaxed);
+            while let Some(request) = read_dot_request(&mut stream) {
+                let mut edns = request.edns.clone().unwrap();
+                assert_eq!(edns.tcp_keepalive(), None);
+                assert_eq!(edns.options[0].code, dns::EDNS_OPTION_TCP_KEEPALIVE);
+
+                // Asks the client to close the connection once idle, or to keep it for a minute
+                let timeout: u16 = match request.question_section[0].qname.to_string().as_str() {
+                    "close.test." => 0,
+                    _ => 600,
+                };
+                edns.options[0].data = timeout.to_be_bytes().to_vec();
+                let mut response = dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR);
+                response.edns = Some(edns);
+                write_dot_response(&mut stream, &response);
+            }
+            closed_sender.lock().unwrap().send(index).unwrap();
+        });
+        let options = DestOptions {
+            keepalive: true,
+            ..test_options()
+        };
+        let client = DotClient::new(addr, "localhost", &options);
+
+        // Connections are opened in turn, then reused within the timeout
+        for _ in 0..DOT_POOL_SIZE * 2 {
+            client.query(dot_request("keep.test")).unwrap();
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), DOT_POOL_SIZE);
+
+        // The first connection learns it must not sit idle, the others are still reused
+        client.query(dot_request("close.test")).unwrap();
+        for _ in 1..DOT_POOL_SIZE {
+            client.query(dot_request("keep.test")).unwrap();
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), DOT_POOL_SIZE);
+
+        // Its turn comes again after the timeout, so it is closed and replaced
+        client.query(dot_request("keep.test")).unwrap();
+        assert_eq!(connections.load(Ordering::Relaxed), DOT_POOL_SIZE + 1);
+        assert_eq!(closed_receiver.recv_timeout(Duration::from_secs(5)), Ok(0));
+    }
+
+    /// Answers DoH GET requests on `listener`, properly for the first `good_responses`, then with
+    /// an error page and a wrong content type.
+    fn spawn_doh_stub(listener: std::net::TcpListener, good_responses: usize) {
+        let requests_served = Arc::new(AtomicUsize::new(0));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let requests_served = requests_served.clone();
+                thread::spawn(move || {
+                    let mut stream = stream.unwrap();
+                    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                    while let Ok(http_request) = crate::http::read_request(&mut reader) {
+                        assert_eq!(http_request.method, "GET");
+                        assert_eq!(http_request.path(), "/dns-query");
+                        let message = URL_SAFE_NO_PAD
+                            .decode(http_request.query_param("dns").unwrap())
+                            .unwrap();
+                        let request = dns::DnsPacket::from_slice(&message).unwrap();
+                        assert_eq!(request.header.id, 0);
+
+                        let response = dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR);
+                        let served = requests_served.fetch_add(1, Ordering::Relaxed);
+                        let (status, content_type) = match served.cmp(&good_responses) {
+                            std::cmp::Ordering::Less => (200, DOH_CONTENT_TYPE),
+                            std::cmp::Ordering::Equal => (404, "text/html"),
+                            std::cmp::Ordering::Greater => (200, "text/html"),
+                        };
+                        crate::http::write_response(
+                            &mut stream,
+                            status,
+                            &[("Content-Type", content_type.to_string())],
+                            &response.bytes(),
+                        )
+                        .unwrap();
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn doh_get_and_bad_responses() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+        let options = test_options();
+        let client = DohClient::new(&url, &[], &options);
+
+        spawn_doh_stub(listener, 1);
+
+        let request = request();
+        let response = client.query(request.clone()).unwrap();
+        assert!(response.is_response_to(&request));
+
+        assert!(matches!(
+            client.query(request.clone()),
+            Err(DestError::HttpStatus(reqwest::StatusCode::NOT_FOUND))
+        ));
+        assert!(matches!(
+            client.query(request),
+            Err(DestError::ContentType(content_type)) if content_type == "text/html"
+        ));
+    }
+
+    #[test]
+    fn doh_json_translates_answers() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/resolve", listener.local_addr().unwrap());
+        let client = DohJsonClient::new(&url, &[], &test_options());
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+            let http_request = crate::http::read_request(&mut reader).unwrap();
+            assert_eq!(http_request.method, "GET");
+            assert_eq!(http_request.path(), "/resolve");
+            assert_eq!(
+                http_request.query_param("name").as_deref(),
+                Some("www.example.com.")
+            );
+            assert_eq!(http_request.query_param("type").as_deref(), Some("1"));
+
+            let body = r#"{"Status": 0, "TC": false, "RD": true, "RA": true,
+                "Question": [{"name": "www.example.com.", "type": 1}],
+                "Answer": [
+                    {"name": "www.example.com.", "type": 5, "TTL": 300, "data": "example.com."},
+                    {"name": "example.com.", "type": 1, "TTL": 60, "data": "93.184.216.34"},
+                    {"name": "example.com.", "type": 15, "TTL": 60, "data": "10 mail.example.com."},
+                    {"name": "example.com.", "type": 16, "TTL": 60, "data": "\"v=spf1 -all\""},
+                    {"name": "example.com.", "type": 46, "TTL": 60,
+                        "data": "a 13 2 60 20261101000000 20261017000000 12345 example.com. c2lnbmF0dXJl"},
+                    {"name": "example.com.", "type": 65, "TTL": 60, "data": "1 . alpn=h2"},
+                    {"name": "example.com.", "type": 65, "TTL": 60, "data": "\\# 3 abcdef"}
+                ],
+                "Authority": [
+                    {"name": "example.com.", "type": 43, "TTL": 60, "data": "2371 13 2 abcdef"}
+                ]}"#;
+            crate::http::write_response(
+                &mut stream,
+                200,
+                &[("Content-Type", "application/dns-json".to_string())],
+                body.as_bytes(),
+            )
+            .unwrap();
+        });
+
+        let request = request();
+        let response = client.query(request.clone()).unwrap();
+        assert!(response.is_response_to(&request));
+        assert_eq!(response.header.rcode(), dns::RCODE_NOERROR);
+
+        let rdatas: Vec<dns::RData> = response
+            .answer_section
+            .into_iter()
+            .map(|answer| answer.rdata)
+            .collect();
+        assert_eq!(
+            rdatas,
+            vec![
+                dns::RData::CNAMERecord {
+                    name: "example.com".parse().unwrap()
+                },
+                dns::RData::ARecord {
+                    ip: "93.184.216.34".parse().unwrap()
+                },
+                dns::RData::MXRecord {
+                    preference: 10,
+                    exchange: "mail.example.com".parse().unwrap()
+                },
+                dns::RData::TXTRecord {
+                    strings: vec![b"v=spf1 -all".to_vec()]
+                },
+                // Records in presentation form that can't be read are left out, not the answer
+                dns::RData::Other {
+                    data: vec![0xab, 0xcd, 0xef]
+                },
+            ]
+        );
+        assert!(response.authority_section.is_empty());
+    }
+
+    #[test]
+    fn doh_json_extended_status() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/resolve", listener.local_addr().unwrap());
+        let options = test_options();
+
+        // BADVERS, which needs the OPT record's upper rcode bits
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                while crate::http::read_request(&mut reader).is_ok() {
+                    crate::http::write_response(
+                        &mut stream,
+                        200,
+                        &[("Content-Type", "application/dns-json".to_string())],
+                        br#"{"Status": 16}"#,
+                    )
+                    .unwrap();
+                }
+            }
+        });
+
+        let mut edns_request = request();
+        edns_request.edns = Some(dns::Edns::new(false));
+        let client = DohJsonClient::new(&url, &[], &options);
+
+        let response = client.query(edns_request.clone()).unwrap();
+        assert_eq!(response.header.rcode(), dns::RCODE_NOERROR);
+        assert_eq!(response.edns.unwrap().extended_rcode, 1);
+        let response = client.query(request()).unwrap();
+        assert_eq!(response.header.rcode(), dns::RCODE_SERVFAIL);
+        assert_eq!(response.edns, None);
+
+        // Upstreams always get EDNS, but clients only get the extended rcode if they sent it
+        let dest_client = DestClient::with_dest(Box::new(client));
+        let response = dest_client.query(edns_request);
+        assert_eq!(response.header.rcode(), dns::RCODE_NOERROR);
+        assert_eq!(response.edns.unwrap().extended_rcode, 1);
+        let response = dest_client.query(request());
+        assert_eq!(response.header.rcode(), dns::RCODE_SERVFAIL);
+        assert_eq!(response.edns, None);
+    }
+
+    #[test]
+    fn doh_bootstraps_hostname() {
+        let bootstrap_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://doh.test:{}/dns-query",
+            listener.local_addr().unwrap().port()
+        );
+        let options = DestOptions {
+            bootstrap: Some(bootstrap_socket.local_addr().unwrap()),
+            ..test_options()
+        };
+        let client = DohClient::new(&url, &[], &options);
+        spawn_doh_stub(listener, 1);
+
+        // Resolves doh.test to 127.0.0.1, with no IPv6 address
+        thread::spawn(move || loop {
+            let mut buf = [0u8; 512];
+            let (number_of_bytes, client_addr) = bootstrap_socket.recv_from(&mut buf).unwrap();
+            let request = dns::DnsPacket::from_slice(&buf[..number_of_bytes]).unwrap();
+            let question = &request.question_section[0];
+            assert_eq!(question.qname, "doh.test".parse().unwrap());
+
+            let mut response = dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR);
+            if question.qtype == dns::TYPE_A {
+                response.answer_section.push(dns::DnsAnswerSection {
+                    name: question.qname.clone(),
+                    atype: dns::TYPE_A,
+                    class: dns::CLASS_IN,
+                    ttl: 300,
+                    rdata: dns::RData::ARecord {
+                        ip: std::net::Ipv4Addr::LOCALHOST,
+                    },
+                });
+            }
+            bootstrap_socket
+                .send_to(&response.bytes(), client_addr)
+                .unwrap();
+        });
+
+        let request = request();
+        let response = client.query(request.clone()).unwrap();
+        assert!(response.is_response_to(&request));
+
+        let refresh_time = client
+            .https
+            .http_client
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .refresh_time;
+        assert!(refresh_time.unwrap() > Instant::now() + Duration::from_secs(290));
+    }
+
+    #[test]
+    fn doh_bootstrap_skips_failed_lookups_and_refreshes_on_expiry() {
+        let bootstrap_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://doh.test:{}/dns-query",
+            listener.local_addr().unwrap().port()
+        );
+        let options = DestOptions {
+            timeout: Duration::from_millis(300),
+            bootstrap: Some(bootstrap_socket.local_addr().unwrap()),
+            ..test_options()
+        };
+        let client = Arc::new(DohClient::new(&url, &[], &options));
+        spawn_doh_stub(listener, usize::MAX);
+
+        // Answers A lookups once the test opens the gate, and never answers AAAA lookups
+        let (asked_sender, asked_receiver) = mpsc::channel();
+        let (gate_sender, gate_receiver) = mpsc::channel::<()>();
+        thread::spawn(move || loop {
+            let mut buf = [0u8; 512];
+            let (number_of_bytes, client_addr) = bootstrap_socket.recv_from(&mut buf).unwrap();
+            let request = dns::DnsPacket::from_slice(&buf[..number_of_bytes]).unwrap();
+            let question = &request.question_section[0];
+            if question.qtype != dns::TYPE_A {
+                continue;
+            }
+            asked_sender.send(()).unwrap();
+            gate_receiver.recv().unwrap();
+
+            let mut response = dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR);
+            response.answer_section.push(dns::DnsAnswerSection {
+                name: question.qname.clone(),
+                atype: dns::TYPE_A,
+                class: dns::CLASS_IN,
+                ttl: 300,
+                rdata: dns::RData::ARecord {
+                    ip: std::net::Ipv4Addr::LOCALHOST,
+                },
+            });
+            bootstrap_socket
+                .send_to(&response.bytes(), client_addr)
+                .unwrap();
+        });
+        let spawn_query = |client: &Arc<DohClient>| {
+            let client = client.clone();
+            let (result_sender, result_receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let request = request();
+                let response = client.query(request.clone());
+                let _ =
+                    result_sender.send(response.map(|response| response.is_response_to(&request)));
+            });
+            result_receiver
+        };
+
+        // The A address is enough
+        gate_sender.send(()).unwrap();
+        let first = spawn_query(&client);
+        assert!(first.recv().unwrap().unwrap());
+
+        // The next query refreshes them itself, and one meanwhile goes ahead with the expired ones
+        client
+            .https
+            .http_client
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .refresh_time = Some(Instant::now());
+        let refreshing = spawn_query(&client);
+        asked_receiver.iter().nth(1).unwrap();
+        let meanwhile = spawn_query(&client);
+        assert!(meanwhile
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap());
+        assert!(refreshing.try_recv().is_err());
+
+        gate_sender.send(()).unwrap();
+        assert!(refreshing.recv().unwrap().unwrap());
+        let refresh_time = client
+            .https
+            .http_client
+            .lock()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .refresh_time;
+        assert!(refresh_time.unwrap() > Instant::now() + Duration::from_secs(290));
+    }
+}
@@ -1,30 +1,90 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::Duration;
 
 use byteorder::{ByteOrder, NetworkEndian};
 use log::*;
 use rand::prelude::*;
 
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_OPT: u16 = 41;
+
+pub const CLASS_IN: u16 = 1;
+
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub enum RData {
-    ARecord { ip: Ipv4Addr },
-    AAAARecord { ip: Ipv6Addr },
-    Other { data: Vec<u8> },
+    ARecord {
+        ip: Ipv4Addr,
+    },
+    AAAARecord {
+        ip: Ipv6Addr,
+    },
+    CNAMERecord {
+        name: DnsName,
+    },
+    NSRecord {
+        name: DnsName,
+    },
+    PTRRecord {
+        name: DnsName,
+    },
+    MXRecord {
+        preference: u16,
+        exchange: DnsName,
+    },
+    TXTRecord {
+        strings: Vec<Vec<u8>>,
+    },
+    SOARecord {
+        mname: DnsName,
+        rname: DnsName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    SRVRecord {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DnsName,
+    },
+    Other {
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DnsName {
+    labels: Vec<Vec<u8>>,
 }
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
 
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub struct DnsAnswerSection {
-    pub name: Vec<u8>,
+    pub name: DnsName,
     pub atype: u16,
     pub class: u16,
     pub ttl: u32,
-    pub rdlength: u16,
     pub rdata: RData,
 }
 
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub struct DnsQuestionSection {
-    pub qname: Vec<u8>,
+    pub qname: DnsName,
     pub qtype: u16,
     pub qclass: u16,
 }
@@ -40,6 +100,36 @@ pub struct DnsHeader {
 }
 const DNS_HEADER_LEN: usize = 12;
 
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_OPCODE_MASK: u16 = 0x7800;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+const FLAG_RCODE_MASK: u16 = 0x000f;
+
+pub const RCODE_NOERROR: u16 = 0;
+pub const RCODE_FORMERR: u16 = 1;
+pub const RCODE_SERVFAIL: u16 = 2;
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The EDNS(0) OPT pseudo-record (RFC 6891), kept out of the additional section.
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+const EDNS_FLAG_DNSSEC_OK: u32 = 0x8000;
+/// edns-tcp-keepalive (RFC 7828), whose timeout counts units of 100 milliseconds
+pub const EDNS_OPTION_TCP_KEEPALIVE: u16 = 11;
+
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub struct DnsPacket {
     pub header: DnsHeader,
@@ -47,44 +137,474 @@ pub struct DnsPacket {
     pub answer_section: Vec<DnsAnswerSection>,
     pub authority_section: Vec<DnsAnswerSection>,
     pub additional_section: Vec<DnsAnswerSection>,
+    pub edns: Option<Edns>,
 }
 
-fn dns_name_bytes_to_string(name_bytes: &[u8]) -> String {
-    let mut name: String = String::from("");
+pub const DNS_UDP_MAX_LEN: usize = 512;
+/// UDP payload size advertised in our own OPT records, small enough to avoid IP fragmentation.
+pub const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// Serializes DNS messages, compressing names against those already written (RFC 1035 4.1.4).
+struct DnsPacketWriter {
+    bytes: Vec<u8>,
+    name_offsets: Option<HashMap<DnsName, u16>>,
+}
 
-    let first_byte = name_bytes[0];
-    if ((3 << 7) & first_byte) != 0 {
-        return String::from("<PTR>");
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DnsParseError {
+    Truncated {
+        offset: usize,
+        needed: usize,
+    },
+    BadLabelLength {
+        offset: usize,
+        length: u8,
+    },
+    PointerLoop {
+        offset: usize,
+        target: usize,
+    },
+    NameTooLong {
+        offset: usize,
+    },
+    CountMismatch {
+        section: &'static str,
+        expected: u16,
+        found: u16,
+    },
+    BadRDataLength {
+        atype: u16,
+        rdlength: u16,
+    },
+    DuplicateOpt,
+}
+
+impl fmt::Display for DnsParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsParseError::Truncated { offset, needed } => {
+                write!(f, "truncated, needed {needed} byte(s) at offset {offset}")
+            }
+            DnsParseError::BadLabelLength { offset, length } => {
+                write!(f, "bad label length {length:#04x} at offset {offset}")
+            }
+            DnsParseError::PointerLoop { offset, target } => write!(
+                f,
+                "compression pointer at offset {offset} does not point backwards (target {target})"
+            ),
+            DnsParseError::NameTooLong { offset } => {
+                write!(
+                    f,
+                    "name at offset {offset} is longer than {MAX_NAME_LEN} bytes"
+                )
+            }
+            DnsParseError::CountMismatch {
+                section,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{section} section has {found} record(s), header promised {expected}"
+            ),
+            DnsParseError::BadRDataLength { atype, rdlength } => {
+                write!(f, "bad rdlength {rdlength} for type {atype}")
+            }
+            DnsParseError::DuplicateOpt => write!(f, "more than one OPT record"),
+        }
     }
+}
 
-    let mut offset = 0;
-    loop {
-        let name_len = name_bytes[offset];
-        offset += 1;
+impl std::error::Error for DnsParseError {}
+
+const LABEL_POINTER_MASK: u8 = 0xc0;
+const LABEL_POINTER_OFFSET_MASK: u16 = 0x3fff;
+
+fn read_bytes(slice: &[u8], offset: usize, len: usize) -> Result<&[u8], DnsParseError> {
+    slice
+        .get(offset..offset + len)
+        .ok_or(DnsParseError::Truncated {
+            offset,
+            needed: len,
+        })
+}
 
-        if name_len == 0 {
-            break;
+fn read_u8(slice: &[u8], offset: usize) -> Result<u8, DnsParseError> {
+    Ok(read_bytes(slice, offset, 1)?[0])
+}
+
+fn read_u16(slice: &[u8], offset: usize) -> Result<u16, DnsParseError> {
+    Ok(NetworkEndian::read_u16(read_bytes(slice, offset, 2)?))
+}
+
+fn read_u32(slice: &[u8], offset: usize) -> Result<u32, DnsParseError> {
+    Ok(NetworkEndian::read_u32(read_bytes(slice, offset, 4)?))
+}
+
+impl DnsPacketWriter {
+    fn new() -> Self {
+        DnsPacketWriter {
+            bytes: Vec::new(),
+            name_offsets: Some(HashMap::new()),
         }
+    }
 
-        let offset_end = offset + name_len as usize;
-        let name_field =
-            std::str::from_utf8(&name_bytes[offset..offset_end]).unwrap_or_else(|error| {
-                error!("Invalid UTF8 string parsing DNS name bytes: {error}");
-                "<ERR>"
-            });
-        offset = offset_end;
+    #[cfg(test)]
+    fn new_uncompressed() -> Self {
+        DnsPacketWriter {
+            bytes: Vec::new(),
+            name_offsets: None,
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn write_u16(&mut self, value: u16) {
+        let mut u16buf = [0; 2];
+        NetworkEndian::write_u16(&mut u16buf, value);
+        self.bytes.extend_from_slice(&u16buf);
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        let mut u32buf = [0; 4];
+        NetworkEndian::write_u32(&mut u32buf, value);
+        self.bytes.extend_from_slice(&u32buf);
+    }
+
+    /// Writes `name`, replacing its longest suffix already in the message with a pointer when
+    /// `compress` is set. Names that may not be compressed can still be pointed to later on.
+    fn write_name(&mut self, name: &DnsName, compress: bool) {
+        for index in 0..name.labels.len() {
+            let suffix = DnsName {
+                labels: name.labels[index..].to_vec(),
+            };
+
+            if let Some(name_offsets) = &mut self.name_offsets {
+                if let (true, Some(&offset)) = (compress, name_offsets.get(&suffix)) {
+                    self.write_u16((LABEL_POINTER_MASK as u16) << 8 | offset);
+                    return;
+                }
+
+                if self.bytes.len() <= LABEL_POINTER_OFFSET_MASK as usize {
+                    name_offsets
+                        .entry(suffix)
+                        .or_insert(self.bytes.len() as u16);
+                }
+            }
+
+            let label = &name.labels[index];
+            self.bytes.push(label.len() as u8);
+            self.bytes.extend_from_slice(label);
+        }
+
+        self.bytes.push(0);
+    }
+
+    /// Writes a placeholder length and returns its offset, to be filled in by `end_length`.
+    fn begin_length(&mut self) -> usize {
+        let offset = self.bytes.len();
+        self.write_u16(0);
+        offset
+    }
+
+    fn end_length(&mut self, offset: usize) {
+        let length = self.bytes.len() - offset - 2;
+        NetworkEndian::write_u16(&mut self.bytes[offset..offset + 2], length as u16);
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl DnsName {
+    pub fn root() -> Self {
+        DnsName { labels: vec![] }
+    }
 
-        name.push_str(name_field);
-        name.push('.');
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
     }
 
-    name
+    /// Whether this is `parent` itself or a name below it.
+    pub fn is_subdomain_of(&self, parent: &DnsName) -> bool {
+        self.labels.len() >= parent.labels.len()
+            && self
+                .labels
+                .iter()
+                .rev()
+                .zip(parent.labels.iter().rev())
+                .all(|(label, parent_label)| label.eq_ignore_ascii_case(parent_label))
+    }
+
+    /// Decodes the name at `offset`, following compression pointers anywhere in `message`.
+    /// Every pointer has to point before the labels it was found in, which rules out pointer loops.
+    fn from_slice(message: &[u8], offset: usize) -> Result<(DnsName, usize), DnsParseError> {
+        let mut labels: Vec<Vec<u8>> = Vec::new();
+        let mut name_len = 1;
+
+        let mut label_offset = offset;
+        let mut segment_start = offset;
+        let mut bytes_read: Option<usize> = None;
+
+        loop {
+            let label_len = read_u8(message, label_offset)?;
+
+            match label_len & LABEL_POINTER_MASK {
+                0 => {
+                    if label_len == 0 {
+                        label_offset += 1;
+                        break;
+                    }
+
+                    let label = read_bytes(message, label_offset, 1 + label_len as usize)?;
+                    name_len += label.len();
+                    if name_len > MAX_NAME_LEN {
+                        return Err(DnsParseError::NameTooLong { offset });
+                    }
+
+                    labels.push(label[1..].to_vec());
+                    label_offset += label.len();
+                }
+                LABEL_POINTER_MASK => {
+                    let target =
+                        (read_u16(message, label_offset)? & LABEL_POINTER_OFFSET_MASK) as usize;
+                    if target >= segment_start {
+                        return Err(DnsParseError::PointerLoop {
+                            offset: label_offset,
+                            target,
+                        });
+                    }
+
+                    if bytes_read.is_none() {
+                        bytes_read = Some(label_offset + 2 - offset);
+                    }
+                    segment_start = target;
+                    label_offset = target;
+                }
+                _ => {
+                    return Err(DnsParseError::BadLabelLength {
+                        offset: label_offset,
+                        length: label_len,
+                    })
+                }
+            }
+        }
+
+        let bytes_read = bytes_read.unwrap_or_else(|| label_offset - offset);
+
+        Ok((DnsName { labels }, bytes_read))
+    }
+}
+
+impl PartialEq for DnsName {
+    fn eq(&self, other: &Self) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(other.labels.iter())
+                .all(|(label, other_label)| label.eq_ignore_ascii_case(other_label))
+    }
+}
+
+impl Eq for DnsName {}
+
+impl Hash for DnsName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.labels.len().hash(state);
+        for label in &self.labels {
+            label.to_ascii_lowercase().hash(state);
+        }
+    }
+}
+
+impl FromStr for DnsName {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        if name.is_empty() {
+            return Ok(DnsName::root());
+        }
+
+        let mut labels: Vec<Vec<u8>> = Vec::new();
+        let mut name_len = 1;
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > MAX_LABEL_LEN {
+                return Err(format!("Invalid label `{label}` in name `{name}`"));
+            }
+
+            name_len += 1 + label.len();
+            labels.push(label.as_bytes().to_vec());
+        }
+
+        if name_len > MAX_NAME_LEN {
+            return Err(format!("Name `{name}` is longer than {MAX_NAME_LEN} bytes"));
+        }
+
+        Ok(DnsName { labels })
+    }
+}
+
+impl fmt::Display for DnsName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.labels.is_empty() {
+            return write!(f, ".");
+        }
+
+        for label in &self.labels {
+            for byte in label {
+                match byte {
+                    b'.' | b'\\' => write!(f, "\\{}", *byte as char)?,
+                    0x21..=0x7e => write!(f, "{}", *byte as char)?,
+                    _ => write!(f, "\\{byte:03}")?,
+                }
+            }
+            write!(f, ".")?;
+        }
+
+        Ok(())
+    }
 }
 
+/// Longest string in a TXT record
+const MAX_CHARACTER_STRING_LEN: usize = 255;
+
 impl RData {
-    fn aaaa_from_slice(slice: &[u8]) -> (RData, usize) {
+    /// Parses record data written in presentation format (RFC 1035 5.1), as JSON DNS APIs
+    /// return it. Any type can also be given in the generic `\# LENGTH HEX` form (RFC 3597 5).
+    pub fn from_text(atype: u16, text: &str) -> Result<RData, String> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let rdata = match (atype, &fields[..]) {
+            (_, ["\\#", length, hex @ ..]) => RData::generic_from_text(length, hex),
+            (TYPE_TXT, _) => {
+                RData::character_strings_from_text(text).map(|strings| RData::TXTRecord { strings })
+            }
+            _ => RData::fields_from_text(atype, &fields),
+        };
+
+        rdata.ok_or_else(|| format!("Bad data `{text}` for record type {atype}"))
+    }
+
+    fn fields_from_text(atype: u16, fields: &[&str]) -> Option<RData> {
+        let rdata = match (atype, fields) {
+            (TYPE_A, [ip]) => RData::ARecord {
+                ip: ip.parse().ok()?,
+            },
+            (TYPE_AAAA, [ip]) => RData::AAAARecord {
+                ip: ip.parse().ok()?,
+            },
+            (TYPE_CNAME, [name]) => RData::CNAMERecord {
+                name: name.parse().ok()?,
+            },
+            (TYPE_NS, [name]) => RData::NSRecord {
+                name: name.parse().ok()?,
+            },
+            (TYPE_PTR, [name]) => RData::PTRRecord {
+                name: name.parse().ok()?,
+            },
+            (TYPE_MX, [preference, exchange]) => RData::MXRecord {
+                preference: preference.parse().ok()?,
+                exchange: exchange.parse().ok()?,
+            },
+            (TYPE_SOA, [mname, rname, serial, refresh, retry, expire, minimum]) => {
+                RData::SOARecord {
+                    mname: mname.parse().ok()?,
+                    rname: rname.parse().ok()?,
+                    serial: serial.parse().ok()?,
+                    refresh: refresh.parse().ok()?,
+                    retry: retry.parse().ok()?,
+                    expire: expire.parse().ok()?,
+                    minimum: minimum.parse().ok()?,
+                }
+            }
+            (TYPE_SRV, [priority, weight, port, target]) => RData::SRVRecord {
+                priority: priority.parse().ok()?,
+                weight: weight.parse().ok()?,
+                port: port.parse().ok()?,
+                target: target.parse().ok()?,
+            },
+            _ => return None,
+        };
+
+        Some(rdata)
+    }
+
+    fn generic_from_text(length: &str, hex: &[&str]) -> Option<RData> {
+        let hex = hex.concat();
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+
+        match data.len() == length.parse::<usize>().ok()? {
+            true => Some(RData::Other { data }),
+            false => None,
+        }
+    }
+
+    /// Parses quoted strings with `\X` and `\DDD` escapes, or unquoted text as one string.
+    /// Strings too long for a TXT record are split up.
+    fn character_strings_from_text(text: &str) -> Option<Vec<Vec<u8>>> {
+        let text = text.trim();
+        let strings: Vec<Vec<u8>> = match text.starts_with('"') {
+            true => {
+                let mut strings: Vec<Vec<u8>> = Vec::new();
+                let mut bytes = text.bytes().peekable();
+                while bytes.peek().is_some() {
+                    if bytes.next()? != b'"' {
+                        return None;
+                    }
+
+                    let mut string: Vec<u8> = Vec::new();
+                    loop {
+                        match bytes.next()? {
+                            b'"' => break,
+                            b'\\' => match bytes.next()? {
+                                digit if digit.is_ascii_digit() => {
+                                    let digits = [digit, bytes.next()?, bytes.next()?];
+                                    string.push(std::str::from_utf8(&digits).ok()?.parse().ok()?);
+                                }
+                                escaped => string.push(escaped),
+                            },
+                            byte => string.push(byte),
+                        }
+                    }
+                    strings.push(string);
+
+                    while bytes.next_if(u8::is_ascii_whitespace).is_some() {}
+                }
+                strings
+            }
+            false => vec![text.as_bytes().to_vec()],
+        };
+
+        Some(
+            strings
+                .iter()
+                .flat_map(|string| match string.is_empty() {
+                    true => vec![vec![]],
+                    false => string
+                        .chunks(MAX_CHARACTER_STRING_LEN)
+                        .map(<[u8]>::to_vec)
+                        .collect(),
+                })
+                .collect(),
+        )
+    }
+
+    fn aaaa_from_slice(slice: &[u8]) -> Result<(RData, usize), DnsParseError> {
         const IPV6_LENGTH: usize = 8;
 
+        if slice.len() != IPV6_LENGTH * 2 {
+            return Err(DnsParseError::BadRDataLength {
+                atype: TYPE_AAAA,
+                rdlength: slice.len() as u16,
+            });
+        }
+
         let mut ip: [u16; IPV6_LENGTH] = [0; 8];
 
         let mut slice_idx = 0;
@@ -99,19 +619,26 @@ impl RData {
         };
         let bytes_read = ip.len() * 2;
 
-        (aaaa_data, bytes_read)
+        Ok((aaaa_data, bytes_read))
     }
 
-    fn a_from_slice(slice: &[u8]) -> (RData, usize) {
+    fn a_from_slice(slice: &[u8]) -> Result<(RData, usize), DnsParseError> {
+        if slice.len() != 4 {
+            return Err(DnsParseError::BadRDataLength {
+                atype: TYPE_A,
+                rdlength: slice.len() as u16,
+            });
+        }
+
         let a_data = RData::ARecord {
             ip: Ipv4Addr::new(slice[0], slice[1], slice[2], slice[3]),
         };
         let bytes_read = 4;
 
-        (a_data, bytes_read)
+        Ok((a_data, bytes_read))
     }
 
-    fn other_from_slice(slice: &[u8]) -> (RData, usize) {
+    fn other_from_slice(slice: &[u8]) -> Result<(RData, usize), DnsParseError> {
         let mut bytes: Vec<u8> = Vec::new();
 
         for byteptr in slice {
@@ -121,39 +648,196 @@ impl RData {
         let bytes_read = bytes.len();
         let other_data = RData::Other { data: bytes };
 
-        (other_data, bytes_read)
+        Ok((other_data, bytes_read))
+    }
+
+    fn name_from_slice(
+        message: &[u8],
+        offset: usize,
+        atype: u16,
+    ) -> Result<(RData, usize), DnsParseError> {
+        let (name, bytes_read) = DnsName::from_slice(message, offset)?;
+
+        let name_data = match atype {
+            TYPE_NS => RData::NSRecord { name },
+            TYPE_PTR => RData::PTRRecord { name },
+            _ => RData::CNAMERecord { name },
+        };
+
+        Ok((name_data, bytes_read))
+    }
+
+    fn mx_from_slice(message: &[u8], offset: usize) -> Result<(RData, usize), DnsParseError> {
+        let preference = read_u16(message, offset)?;
+        let (exchange, name_len) = DnsName::from_slice(message, offset + 2)?;
+
+        let mx_data = RData::MXRecord {
+            preference,
+            exchange,
+        };
+        let bytes_read = 2 + name_len;
+
+        Ok((mx_data, bytes_read))
+    }
+
+    fn txt_from_slice(slice: &[u8]) -> Result<(RData, usize), DnsParseError> {
+        let mut strings: Vec<Vec<u8>> = Vec::new();
+
+        let mut string_offset = 0;
+        while string_offset < slice.len() {
+            let string_len = read_u8(slice, string_offset)? as usize;
+            let string = read_bytes(slice, string_offset + 1, string_len)?;
+            strings.push(string.to_vec());
+            string_offset += 1 + string_len;
+        }
+
+        let txt_data = RData::TXTRecord { strings };
+        let bytes_read = string_offset;
+
+        Ok((txt_data, bytes_read))
+    }
+
+    fn soa_from_slice(message: &[u8], offset: usize) -> Result<(RData, usize), DnsParseError> {
+        let (mname, name_len) = DnsName::from_slice(message, offset)?;
+        let mut field_offset = offset + name_len;
+
+        let (rname, name_len) = DnsName::from_slice(message, field_offset)?;
+        field_offset += name_len;
+
+        let mut values: [u32; 5] = [0; 5];
+        for value in &mut values {
+            *value = read_u32(message, field_offset)?;
+            field_offset += 4;
+        }
+
+        let soa_data = RData::SOARecord {
+            mname,
+            rname,
+            serial: values[0],
+            refresh: values[1],
+            retry: values[2],
+            expire: values[3],
+            minimum: values[4],
+        };
+        let bytes_read = field_offset - offset;
+
+        Ok((soa_data, bytes_read))
     }
 
-    fn from_slice(slice: &[u8], atype: Option<u16>) -> (RData, usize) {
-        match atype {
+    fn srv_from_slice(message: &[u8], offset: usize) -> Result<(RData, usize), DnsParseError> {
+        let priority = read_u16(message, offset)?;
+        let weight = read_u16(message, offset + 2)?;
+        let port = read_u16(message, offset + 4)?;
+        let (target, name_len) = DnsName::from_slice(message, offset + 6)?;
+
+        let srv_data = RData::SRVRecord {
+            priority,
+            weight,
+            port,
+            target,
+        };
+        let bytes_read = 6 + name_len;
+
+        Ok((srv_data, bytes_read))
+    }
+
+    fn from_slice(
+        message: &[u8],
+        offset: usize,
+        rdlength: u16,
+        atype: Option<u16>,
+    ) -> Result<(RData, usize), DnsParseError> {
+        let slice = read_bytes(message, offset, rdlength as usize)?;
+
+        let result = match atype {
             None => RData::other_from_slice(slice),
             Some(atype) => match atype {
-                1 => RData::a_from_slice(slice),
-                28 => RData::aaaa_from_slice(slice),
+                TYPE_A => RData::a_from_slice(slice),
+                TYPE_AAAA => RData::aaaa_from_slice(slice),
+                TYPE_NS | TYPE_CNAME | TYPE_PTR => RData::name_from_slice(message, offset, atype),
+                TYPE_MX => RData::mx_from_slice(message, offset),
+                TYPE_TXT => RData::txt_from_slice(slice),
+                TYPE_SOA => RData::soa_from_slice(message, offset),
+                TYPE_SRV => RData::srv_from_slice(message, offset),
                 _ => RData::other_from_slice(slice),
             },
+        };
+
+        // Names may be compressed, so a record is only checked against its rdlength once parsed
+        match result {
+            Ok((_, bytes_read)) if bytes_read != rdlength as usize => {
+                Err(DnsParseError::BadRDataLength {
+                    atype: atype.unwrap_or_default(),
+                    rdlength,
+                })
+            }
+            Err(DnsParseError::Truncated { .. }) if atype == Some(TYPE_TXT) => {
+                Err(DnsParseError::BadRDataLength {
+                    atype: TYPE_TXT,
+                    rdlength,
+                })
+            }
+            result => result,
         }
     }
 
-    fn bytes(&self) -> Vec<u8> {
-        let mut result: Vec<u8> = Vec::new();
-
+    fn write(&self, writer: &mut DnsPacketWriter) {
         match self {
-            RData::ARecord { ip } => {
-                for byteptr in ip.octets().iter() {
-                    result.push(*byteptr);
+            RData::ARecord { ip } => writer.write_bytes(&ip.octets()),
+            RData::AAAARecord { ip } => writer.write_bytes(&ip.octets()),
+            RData::CNAMERecord { name } | RData::NSRecord { name } | RData::PTRRecord { name } => {
+                writer.write_name(name, true)
+            }
+            RData::MXRecord {
+                preference,
+                exchange,
+            } => {
+                writer.write_u16(*preference);
+                writer.write_name(exchange, true);
+            }
+            RData::TXTRecord { strings } => {
+                for string in strings {
+                    writer.write_bytes(&[string.len() as u8]);
+                    writer.write_bytes(string);
                 }
-                result
             }
-            RData::AAAARecord { ip } => {
-                for byteptr in ip.octets().iter() {
-                    result.push(*byteptr);
+            RData::SOARecord {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                writer.write_name(mname, true);
+                writer.write_name(rname, true);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    writer.write_u32(*value);
                 }
-                result
             }
-            RData::Other { data } => data.to_vec(),
+            RData::SRVRecord {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                writer.write_u16(*priority);
+                writer.write_u16(*weight);
+                writer.write_u16(*port);
+                // RFC 2782 forbids compressing the target
+                writer.write_name(target, false);
+            }
+            RData::Other { data } => writer.write_bytes(data),
         }
     }
+
+    #[cfg(test)]
+    fn bytes(&self) -> Vec<u8> {
+        let mut writer = DnsPacketWriter::new_uncompressed();
+        self.write(&mut writer);
+        writer.into_bytes()
+    }
 }
 
 impl fmt::Display for RData {
@@ -161,100 +845,103 @@ impl fmt::Display for RData {
         match self {
             RData::ARecord { ip } => write!(f, "A:{}", ip),
             RData::AAAARecord { ip } => write!(f, "AAAA:{}", ip),
+            RData::CNAMERecord { name } => write!(f, "CNAME:{}", name),
+            RData::NSRecord { name } => write!(f, "NS:{}", name),
+            RData::PTRRecord { name } => write!(f, "PTR:{}", name),
+            RData::MXRecord {
+                preference,
+                exchange,
+            } => write!(f, "MX:{} {}", preference, exchange),
+            RData::TXTRecord { strings } => {
+                write!(f, "TXT:")?;
+                for (index, string) in strings.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "\"{}\"", string.escape_ascii())?;
+                }
+                Ok(())
+            }
+            RData::SOARecord {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "SOA:{} {} {} {} {} {} {}",
+                mname, rname, serial, refresh, retry, expire, minimum
+            ),
+            RData::SRVRecord {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "SRV:{} {} {} {}", priority, weight, port, target),
             RData::Other { data } => write!(f, "?:{:?}", data),
         }
     }
 }
 
 impl DnsAnswerSection {
-    fn from_slice(slice: &[u8]) -> (DnsAnswerSection, usize) {
-        let mut offset = 0;
-        let mut aname: Vec<u8> = Vec::new();
-
-        loop {
-            let aname_field_len = slice[offset];
-            aname.push(slice[offset]);
-            offset += 1;
+    fn from_slice(
+        message: &[u8],
+        offset: usize,
+    ) -> Result<(DnsAnswerSection, usize), DnsParseError> {
+        let (aname, name_len) = DnsName::from_slice(message, offset)?;
+        let mut field_offset = offset + name_len;
 
-            if aname_field_len == 0 {
-                break;
-            }
+        let atype = read_u16(message, field_offset)?;
+        field_offset += 2;
 
-            if aname_field_len == 192 {
-                aname.push(slice[offset]);
-                offset += 1;
+        let aclass = read_u16(message, field_offset)?;
+        field_offset += 2;
 
-                break;
-            } else {
-                let offset_end = offset + aname_field_len as usize;
+        let attl = read_u32(message, field_offset)?;
+        field_offset += 4;
 
-                for byteptr in &slice[offset..offset_end] {
-                    aname.push(*byteptr);
-                }
-                offset = offset_end;
-            }
-        }
+        let rdlength = read_u16(message, field_offset)?;
+        field_offset += 2;
 
-        let offset_end = offset + 2;
-        let atype = NetworkEndian::read_u16(&slice[offset..offset_end]);
-        offset = offset_end;
-
-        let offset_end = offset + 2;
-        let aclass = NetworkEndian::read_u16(&slice[offset..offset_end]);
-        offset = offset_end;
-
-        let offset_end = offset + 4;
-        let attl = NetworkEndian::read_u32(&slice[offset..offset_end]);
-        offset = offset_end;
-
-        let offset_end = offset + 2;
-        let rdlength = NetworkEndian::read_u16(&slice[offset..offset_end]);
-        offset = offset_end;
-
-        let offset_end = offset + rdlength as usize;
-        let result = RData::from_slice(&slice[offset..offset_end], Some(atype));
+        let result = RData::from_slice(message, field_offset, rdlength, Some(atype))?;
         let rdata = result.0;
-        offset += result.1;
+        field_offset += result.1;
 
         let dns_answer_section = DnsAnswerSection {
             name: aname,
             atype,
             class: aclass,
             ttl: attl,
-            rdlength,
             rdata,
         };
-        let bytes_read = offset;
+        let bytes_read = field_offset - offset;
 
-        (dns_answer_section, bytes_read)
+        Ok((dns_answer_section, bytes_read))
     }
 
     pub fn name_string(&self) -> String {
-        dns_name_bytes_to_string(&self.name)
+        self.name.to_string()
     }
 
-    fn bytes(&self) -> Vec<u8> {
-        let mut result: Vec<u8> = Vec::new();
-        let mut u16buf = [0; 2];
-        let mut u32buf = [0; 4];
-
-        result.extend(self.name.iter());
+    fn write(&self, writer: &mut DnsPacketWriter) {
+        writer.write_name(&self.name, true);
+        writer.write_u16(self.atype);
+        writer.write_u16(self.class);
+        writer.write_u32(self.ttl);
 
-        NetworkEndian::write_u16(&mut u16buf, self.atype);
-        result.extend_from_slice(&u16buf);
-
-        NetworkEndian::write_u16(&mut u16buf, self.class);
-        result.extend_from_slice(&u16buf);
-
-        NetworkEndian::write_u32(&mut u32buf, self.ttl);
-        result.extend_from_slice(&u32buf);
-
-        NetworkEndian::write_u16(&mut u16buf, self.rdlength);
-        result.extend_from_slice(&u16buf);
-
-        result.extend(self.rdata.bytes().iter());
+        let rdlength_offset = writer.begin_length();
+        self.rdata.write(writer);
+        writer.end_length(rdlength_offset);
+    }
 
-        result
+    #[cfg(test)]
+    fn bytes(&self) -> Vec<u8> {
+        let mut writer = DnsPacketWriter::new_uncompressed();
+        self.write(&mut writer);
+        writer.into_bytes()
     }
 }
 
@@ -262,73 +949,55 @@ impl fmt::Display for DnsAnswerSection {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "name:{}, type:{}, class:{}, ttl:{}, rdlength:{}, rdata:{}",
+            "name:{}, type:{}, class:{}, ttl:{}, rdata:{}",
             self.name_string(),
             self.atype,
             self.class,
             self.ttl,
-            self.rdlength,
             self.rdata
         )
     }
 }
 
 impl DnsQuestionSection {
-    fn from_slice(slice: &[u8]) -> (DnsQuestionSection, usize) {
-        let mut offset = 0;
-        let mut qname: Vec<u8> = Vec::new();
-
-        loop {
-            let qname_field_len = slice[offset];
-            qname.push(slice[offset]);
-            offset += 1;
-
-            if qname_field_len == 0 {
-                break;
-            }
-
-            let offset_end = offset + qname_field_len as usize;
-            for byteptr in &slice[offset..offset_end] {
-                qname.push(*byteptr);
-            }
-            offset = offset_end;
-        }
+    fn from_slice(
+        message: &[u8],
+        offset: usize,
+    ) -> Result<(DnsQuestionSection, usize), DnsParseError> {
+        let (qname, name_len) = DnsName::from_slice(message, offset)?;
+        let mut field_offset = offset + name_len;
 
-        let offset_end = offset + 2;
-        let qtype = NetworkEndian::read_u16(&slice[offset..offset_end]);
-        offset = offset_end;
+        let qtype = read_u16(message, field_offset)?;
+        field_offset += 2;
 
-        let offset_end = offset + 2;
-        let qclass = NetworkEndian::read_u16(&slice[offset..offset_end]);
-        offset = offset_end;
+        let qclass = read_u16(message, field_offset)?;
+        field_offset += 2;
 
         let dns_question_section = DnsQuestionSection {
             qname,
             qtype,
             qclass,
         };
-        let bytes_read = offset;
+        let bytes_read = field_offset - offset;
 
-        (dns_question_section, bytes_read)
+        Ok((dns_question_section, bytes_read))
     }
 
     pub fn name_string(&self) -> String {
-        dns_name_bytes_to_string(&self.qname)
+        self.qname.to_string()
     }
 
-    fn bytes(&self) -> Vec<u8> {
-        let mut result: Vec<u8> = Vec::new();
-        let mut u16buf = [0; 2];
-
-        result.extend(self.qname.iter());
-
-        NetworkEndian::write_u16(&mut u16buf, self.qtype);
-        result.extend_from_slice(&u16buf);
-
-        NetworkEndian::write_u16(&mut u16buf, self.qclass);
-        result.extend_from_slice(&u16buf);
+    fn write(&self, writer: &mut DnsPacketWriter) {
+        writer.write_name(&self.qname, true);
+        writer.write_u16(self.qtype);
+        writer.write_u16(self.qclass);
+    }
 
-        result
+    #[cfg(test)]
+    fn bytes(&self) -> Vec<u8> {
+        let mut writer = DnsPacketWriter::new_uncompressed();
+        self.write(&mut writer);
+        writer.into_bytes()
     }
 }
 
@@ -345,7 +1014,8 @@ impl fmt::Display for DnsQuestionSection {
 }
 
 impl DnsHeader {
-    fn from_slice(slice: &[u8]) -> (DnsHeader, usize) {
+    fn from_slice(slice: &[u8]) -> Result<(DnsHeader, usize), DnsParseError> {
+        let slice = read_bytes(slice, 0, DNS_HEADER_LEN)?;
         let dns_header = DnsHeader {
             id: NetworkEndian::read_u16(&slice[0..2]),
             flags: NetworkEndian::read_u16(&slice[2..4]),
@@ -357,7 +1027,7 @@ impl DnsHeader {
 
         let bytes_read = DNS_HEADER_LEN;
 
-        (dns_header, bytes_read)
+        Ok((dns_header, bytes_read))
     }
 
     fn bytes(&self) -> Vec<u8> {
@@ -386,10 +1056,45 @@ impl DnsHeader {
     }
 
     pub fn isrequest(&self) -> bool {
-        let result = self.flags & 0x8000;
+        let result = self.flags & FLAG_RESPONSE;
         result == 0
     }
 
+    pub fn set_recursion_desired(&mut self, recursion_desired: bool) {
+        match recursion_desired {
+            true => self.flags |= FLAG_RECURSION_DESIRED,
+            false => self.flags &= !FLAG_RECURSION_DESIRED,
+        }
+    }
+
+    pub fn set_truncated(&mut self, truncated: bool) {
+        match truncated {
+            true => self.flags |= FLAG_TRUNCATED,
+            false => self.flags &= !FLAG_TRUNCATED,
+        }
+    }
+
+    pub fn istruncated(&self) -> bool {
+        self.flags & FLAG_TRUNCATED != 0
+    }
+
+    pub fn rcode(&self) -> u16 {
+        self.flags & FLAG_RCODE_MASK
+    }
+
+    pub fn set_rcode(&mut self, rcode: u16) {
+        self.flags = (self.flags & !FLAG_RCODE_MASK) | (rcode & FLAG_RCODE_MASK);
+    }
+
+    pub fn opcode(&self) -> u16 {
+        (self.flags & FLAG_OPCODE_MASK) >> 11
+    }
+
+    fn response_flags(&self, rcode: u16) -> u16 {
+        let echoed_flags = self.flags & (FLAG_OPCODE_MASK | FLAG_RECURSION_DESIRED);
+        FLAG_RESPONSE | echoed_flags | FLAG_RECURSION_AVAILABLE | (rcode & FLAG_RCODE_MASK)
+    }
+
     fn new(id: u16) -> Self {
         DnsHeader {
             id,
@@ -416,41 +1121,157 @@ impl fmt::Display for DnsHeader {
     }
 }
 
+impl Edns {
+    pub fn new(dnssec_ok: bool) -> Self {
+        Edns {
+            udp_payload_size: EDNS_UDP_PAYLOAD_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok,
+            options: vec![],
+        }
+    }
+
+    /// Asks the server how long it keeps idle TCP connections open, with an empty
+    /// edns-tcp-keepalive option as clients must send (RFC 7828 3.2.1).
+    pub fn request_tcp_keepalive(&mut self) {
+        self.options.push(EdnsOption {
+            code: EDNS_OPTION_TCP_KEEPALIVE,
+            data: vec![],
+        });
+    }
+
+    /// The idle timeout a server advertised in its edns-tcp-keepalive option, if any.
+    pub fn tcp_keepalive(&self) -> Option<Duration> {
+        let option = self
+            .options
+            .iter()
+            .find(|option| option.code == EDNS_OPTION_TCP_KEEPALIVE)?;
+        let timeout = read_u16(&option.data, 0).ok()?;
+
+        Some(Duration::from_millis(u64::from(timeout) * 100))
+    }
+
+    /// Decodes an OPT record, which reuses the class as the payload size and the TTL as flags.
+    fn from_record(record: &DnsAnswerSection) -> Result<Edns, DnsParseError> {
+        let data: &[u8] = match &record.rdata {
+            RData::Other { data } => data,
+            _ => &[],
+        };
+
+        let mut options: Vec<EdnsOption> = Vec::new();
+        let mut option_offset = 0;
+        while option_offset < data.len() {
+            let result = Edns::option_from_slice(data, option_offset).map_err(|_| {
+                DnsParseError::BadRDataLength {
+                    atype: TYPE_OPT,
+                    rdlength: data.len() as u16,
+                }
+            })?;
+            options.push(result.0);
+            option_offset += result.1;
+        }
+
+        Ok(Edns {
+            udp_payload_size: record.class,
+            extended_rcode: (record.ttl >> 24) as u8,
+            version: (record.ttl >> 16) as u8,
+            dnssec_ok: record.ttl & EDNS_FLAG_DNSSEC_OK != 0,
+            options,
+        })
+    }
+
+    fn option_from_slice(
+        slice: &[u8],
+        offset: usize,
+    ) -> Result<(EdnsOption, usize), DnsParseError> {
+        let code = read_u16(slice, offset)?;
+        let len = read_u16(slice, offset + 2)? as usize;
+        let data = read_bytes(slice, offset + 4, len)?;
+
+        let option = EdnsOption {
+            code,
+            data: data.to_vec(),
+        };
+        let bytes_read = 4 + len;
+
+        Ok((option, bytes_read))
+    }
+
+    fn write(&self, writer: &mut DnsPacketWriter) {
+        let mut flags = (self.extended_rcode as u32) << 24 | (self.version as u32) << 16;
+        if self.dnssec_ok {
+            flags |= EDNS_FLAG_DNSSEC_OK;
+        }
+
+        writer.write_name(&DnsName::root(), false);
+        writer.write_u16(TYPE_OPT);
+        writer.write_u16(self.udp_payload_size);
+        writer.write_u32(flags);
+
+        let rdlength_offset = writer.begin_length();
+        for option in &self.options {
+            writer.write_u16(option.code);
+            writer.write_u16(option.data.len() as u16);
+            writer.write_bytes(&option.data);
+        }
+        writer.end_length(rdlength_offset);
+    }
+}
+
+impl fmt::Display for Edns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "udp:{}, ext_rcode:{}, version:{}, do:{}, options:[",
+            self.udp_payload_size, self.extended_rcode, self.version, self.dnssec_ok
+        )?;
+        for (index, option) in self.options.iter().enumerate() {
+            if index > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}:{:?}", option.code, option.data)?;
+        }
+        write!(f, "]")
+    }
+}
+
 impl DnsPacket {
-    pub fn from_slice(slice: &[u8]) -> DnsPacket {
+    pub fn from_slice(slice: &[u8]) -> Result<DnsPacket, DnsParseError> {
         let mut offset = 0;
 
-        let result = DnsHeader::from_slice(&slice[offset..]);
+        let result = DnsHeader::from_slice(slice)?;
         let dns_header = result.0;
         offset += result.1;
 
         let mut questions: Vec<DnsQuestionSection> = Vec::new();
         for _i in 0..dns_header.qdcount {
-            let result = DnsQuestionSection::from_slice(&slice[offset..]);
+            DnsPacket::check_count(slice, offset, "question", dns_header.qdcount, &questions)?;
+            let result = DnsQuestionSection::from_slice(slice, offset)?;
             questions.push(result.0);
             offset += result.1;
         }
 
-        let mut answers: Vec<DnsAnswerSection> = Vec::new();
-        for _i in 0..dns_header.ancount {
-            let result = DnsAnswerSection::from_slice(&slice[offset..]);
-            answers.push(result.0);
-            offset += result.1;
-        }
+        let result = DnsPacket::records_from_slice(slice, offset, "answer", dns_header.ancount)?;
+        let answers = result.0;
+        offset += result.1;
 
-        let mut authorities: Vec<DnsAnswerSection> = Vec::new();
-        for _i in 0..dns_header.nscount {
-            let result = DnsAnswerSection::from_slice(&slice[offset..]);
-            authorities.push(result.0);
-            offset += result.1;
-        }
+        let result = DnsPacket::records_from_slice(slice, offset, "authority", dns_header.nscount)?;
+        let authorities = result.0;
+        offset += result.1;
 
-        let mut additionals: Vec<DnsAnswerSection> = Vec::new();
-        for _i in 0..dns_header.arcount {
-            let result = DnsAnswerSection::from_slice(&slice[offset..]);
-            additionals.push(result.0);
-            offset += result.1;
-        }
+        let result =
+            DnsPacket::records_from_slice(slice, offset, "additional", dns_header.arcount)?;
+        let (opts, additionals): (Vec<DnsAnswerSection>, Vec<DnsAnswerSection>) = result
+            .0
+            .into_iter()
+            .partition(|record| record.atype == TYPE_OPT);
+
+        let edns = match opts.as_slice() {
+            [] => None,
+            [opt] => Some(Edns::from_record(opt)?),
+            _ => return Err(DnsParseError::DuplicateOpt),
+        };
 
         let dns_packet = DnsPacket {
             header: dns_header,
@@ -458,35 +1279,123 @@ impl DnsPacket {
             answer_section: answers,
             authority_section: authorities,
             additional_section: additionals,
+            edns,
         };
 
         debug!("Parsed DNS: {}", dns_packet);
 
-        dns_packet
+        Ok(dns_packet)
     }
 
+    fn records_from_slice(
+        slice: &[u8],
+        offset: usize,
+        section: &'static str,
+        count: u16,
+    ) -> Result<(Vec<DnsAnswerSection>, usize), DnsParseError> {
+        let mut records: Vec<DnsAnswerSection> = Vec::new();
+        let mut record_offset = offset;
+
+        for _i in 0..count {
+            DnsPacket::check_count(slice, record_offset, section, count, &records)?;
+            let result = DnsAnswerSection::from_slice(slice, record_offset)?;
+            records.push(result.0);
+            record_offset += result.1;
+        }
+
+        let bytes_read = record_offset - offset;
+
+        Ok((records, bytes_read))
+    }
+
+    fn check_count<T>(
+        slice: &[u8],
+        offset: usize,
+        section: &'static str,
+        expected: u16,
+        found: &[T],
+    ) -> Result<(), DnsParseError> {
+        if offset < slice.len() {
+            return Ok(());
+        }
+
+        Err(DnsParseError::CountMismatch {
+            section,
+            expected,
+            found: found.len() as u16,
+        })
+    }
+
+    /// Serializes the packet with compressed names. The header counts are taken from the sections.
     pub fn bytes(&self) -> Vec<u8> {
-        let mut result: Vec<u8> = Vec::new();
+        let mut writer = DnsPacketWriter::new();
 
-        result.extend(self.header.bytes().iter());
+        let mut dns_header = self.header.clone();
+        dns_header.qdcount = self.question_section.len() as u16;
+        dns_header.ancount = self.answer_section.len() as u16;
+        dns_header.nscount = self.authority_section.len() as u16;
+        dns_header.arcount = self.additional_section.len() as u16 + self.edns.is_some() as u16;
+        writer.write_bytes(&dns_header.bytes());
 
         for question in &self.question_section {
-            result.extend(question.bytes().iter());
+            question.write(&mut writer);
         }
 
         for answer in &self.answer_section {
-            result.extend(answer.bytes().iter());
+            answer.write(&mut writer);
         }
 
         for authority in &self.authority_section {
-            result.extend(authority.bytes().iter());
+            authority.write(&mut writer);
         }
 
         for additional in &self.additional_section {
-            result.extend(additional.bytes().iter());
+            additional.write(&mut writer);
         }
 
-        result
+        if let Some(edns) = &self.edns {
+            edns.write(&mut writer);
+        }
+
+        writer.into_bytes()
+    }
+
+    /// Serializes the packet to at most `max_len` bytes. Authority and additional records are
+    /// dropped first (the OPT record is kept); if the answers still do not fit they are dropped
+    /// too and TC is set.
+    pub fn bytes_truncated(&self, max_len: usize) -> Vec<u8> {
+        let bytes = self.bytes();
+        if bytes.len() <= max_len {
+            return bytes;
+        }
+
+        let mut truncated = self.clone();
+        truncated.authority_section.clear();
+        truncated.additional_section.clear();
+
+        let bytes = truncated.bytes();
+        if bytes.len() <= max_len {
+            return bytes;
+        }
+
+        debug!(
+            "Truncating response {} ({} > {max_len} bytes)",
+            self.header.id,
+            bytes.len()
+        );
+        truncated.answer_section.clear();
+        truncated.header.flags |= FLAG_TRUNCATED;
+        truncated.bytes()
+    }
+
+    /// The largest response this request accepts over UDP: its advertised EDNS payload size,
+    /// capped to our own, or 512 bytes without EDNS.
+    pub fn max_udp_response_len(&self) -> usize {
+        match &self.edns {
+            Some(edns) => usize::from(edns.udp_payload_size)
+                .clamp(DNS_UDP_MAX_LEN, EDNS_UDP_PAYLOAD_SIZE.into()),
+            None => DNS_UDP_MAX_LEN,
+        }
     }
 
     pub fn new_with_questions(questions: Vec<DnsQuestionSection>) -> DnsPacket {
@@ -499,6 +1408,7 @@ impl DnsPacket {
             answer_section: vec![],
             authority_section: vec![],
             additional_section: vec![],
+            edns: None,
         };
 
         debug!("Generated DNS: {}", dns_packet);
@@ -506,6 +1416,52 @@ impl DnsPacket {
         dns_packet
     }
 
+    /// Whether this answers `request`, matching its ID, opcode and question (RFC 5452 4.3).
+    pub fn is_response_to(&self, request: &DnsPacket) -> bool {
+        !self.header.isrequest()
+            && self.header.id == request.header.id
+            && self.header.opcode() == request.header.opcode()
+            && self.question_section == request.question_section
+    }
+
+    pub fn new_response(request: &DnsPacket, rcode: u16) -> DnsPacket {
+        let mut dns_header = DnsHeader::new(request.header.id);
+        dns_header.flags = request.header.response_flags(rcode);
+        dns_header.add_to_question_section(request.question_section.len() as u16);
+
+        let dns_packet = DnsPacket {
+            header: dns_header,
+            question_section: request.question_section.clone(),
+            answer_section: vec![],
+            authority_section: vec![],
+            additional_section: vec![],
+            edns: request.edns.as_ref().map(|edns| Edns::new(edns.dnssec_ok)),
+        };
+
+        debug!("Generated DNS: {}", dns_packet);
+
+        dns_packet
+    }
+
+    /// Builds a FORMERR response to a request that failed to parse, if its header is readable.
+    pub fn new_format_error_response(slice: &[u8]) -> Option<DnsPacket> {
+        let (dns_header, _) = DnsHeader::from_slice(slice).ok()?;
+        if !dns_header.isrequest() {
+            return None;
+        }
+
+        let request = DnsPacket {
+            header: dns_header,
+            question_section: vec![],
+            answer_section: vec![],
+            authority_section: vec![],
+            additional_section: vec![],
+            edns: None,
+        };
+
+        Some(DnsPacket::new_response(&request, RCODE_FORMERR))
+    }
+
     pub fn add_to_answer_section(&mut self, answers: &[DnsAnswerSection]) {
         self.answer_section.extend_from_slice(answers);
         self.header.ancount += answers.len() as u16;
@@ -543,6 +1499,11 @@ impl fmt::Display for DnsPacket {
             write!(f, "{{ {} }}", entry)?;
         }
         write!(f, "]")?;
+
+        if let Some(edns) = &self.edns {
+            write!(f, " ")?;
+            write!(f, "OPT[{{ {} }}]", edns)?;
+        }
         Ok(())
     }
 }
@@ -554,7 +1515,7 @@ mod tests {
     #[test]
     fn rdata_serialize_deserialize_ipv4() {
         let raw_rdata: [u8; 4] = [0x3f, 0xf5, 0xd0, 0xc3];
-        let rdata_struct = RData::from_slice(&raw_rdata, Some(1)); // A
+        let rdata_struct = RData::from_slice(&raw_rdata, 0, 4, Some(1)).unwrap(); // A
         let rdata_bytes = rdata_struct.0.bytes();
 
         assert_eq!(raw_rdata, rdata_bytes.as_ref());
@@ -566,7 +1527,7 @@ mod tests {
             0x20, 0x01, 0x41, 0xd0, 0x03, 0x02, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
             0x76, 0x15,
         ];
-        let rdata_struct = RData::from_slice(&raw_rdata, Some(28)); // AAAA
+        let rdata_struct = RData::from_slice(&raw_rdata, 0, 16, Some(28)).unwrap(); // AAAA
         let rdata_bytes = rdata_struct.0.bytes();
 
         assert_eq!(raw_rdata, rdata_bytes.as_ref());
@@ -575,25 +1536,178 @@ mod tests {
     #[test]
     fn rdata_serialize_deserialize_other() {
         let raw_rdata = b"\x06\x74\x77\x69\x74\x63\x68\x03\x6d\x61\x70\x06\x66\x61\x73\x74\x6c\x79\x03\x6e\x65\x74\x00";
-        let rdata_struct = RData::from_slice(raw_rdata, Some(5)); // CNAME
+        let rdata_struct =
+            RData::from_slice(raw_rdata, 0, raw_rdata.len() as u16, Some(5)).unwrap(); // CNAME
         let rdata_bytes = rdata_struct.0.bytes();
 
         assert_eq!(raw_rdata, rdata_bytes.as_slice());
     }
 
+    #[test]
+    fn rdata_serialize_deserialize_mx() {
+        let raw_rdata =
+            b"\x00\x0a\x04\x6d\x61\x69\x6c\x07\x65\x78\x61\x6d\x70\x6c\x65\x03\x63\x6f\x6d\x00";
+        let rdata_struct =
+            RData::from_slice(raw_rdata, 0, raw_rdata.len() as u16, Some(TYPE_MX)).unwrap();
+        let rdata_bytes = rdata_struct.0.bytes();
+
+        assert_eq!(raw_rdata, rdata_bytes.as_slice());
+        assert_eq!(rdata_struct.0.to_string(), "MX:10 mail.example.com.");
+    }
+
+    #[test]
+    fn rdata_serialize_deserialize_txt() {
+        let raw_rdata = b"\x0bv=spf1 -all\x05a\"b\x01c";
+        let rdata_struct =
+            RData::from_slice(raw_rdata, 0, raw_rdata.len() as u16, Some(TYPE_TXT)).unwrap();
+        let rdata_bytes = rdata_struct.0.bytes();
+
+        assert_eq!(raw_rdata, rdata_bytes.as_slice());
+        assert_eq!(
+            rdata_struct.0.to_string(),
+            "TXT:\"v=spf1 -all\" \"a\\\"b\\x01c\""
+        );
+    }
+
+    #[test]
+    fn rdata_serialize_deserialize_soa() {
+        let raw_rdata = b"\
+\x03\x6e\x73\x31\x07\x65\x78\x61\x6d\x70\x6c\x65\x03\x63\x6f\x6d\x00\
+\x05\x61\x64\x6d\x69\x6e\x07\x65\x78\x61\x6d\x70\x6c\x65\x03\x63\x6f\x6d\x00\
+\x78\x49\x6d\x8d\x00\x00\x1c\x20\x00\x00\x0e\x10\x00\x12\x75\x00\x00\x00\x0e\x10";
+        let rdata_struct =
+            RData::from_slice(raw_rdata, 0, raw_rdata.len() as u16, Some(TYPE_SOA)).unwrap();
+        let rdata_bytes = rdata_struct.0.bytes();
+
+        assert_eq!(raw_rdata, rdata_bytes.as_slice());
+        assert_eq!(
+            rdata_struct.0.to_string(),
+            "SOA:ns1.example.com. admin.example.com. 2018078093 7200 3600 1209600 3600"
+        );
+    }
+
+    #[test]
+    fn rdata_serialize_deserialize_srv() {
+        let raw_rdata = b"\x00\x0a\x00\x3c\x14\x95\x03\x73\x69\x70\x07\x65\x78\x61\x6d\x70\x6c\x65\x03\x63\x6f\x6d\x00";
+        let rdata_struct =
+            RData::from_slice(raw_rdata, 0, raw_rdata.len() as u16, Some(TYPE_SRV)).unwrap();
+        let rdata_bytes = rdata_struct.0.bytes();
+
+        assert_eq!(raw_rdata, rdata_bytes.as_slice());
+        assert_eq!(
+            rdata_struct.0.to_string(),
+            "SRV:10 60 5269 sip.example.com."
+        );
+    }
+
+    #[test]
+    fn rdata_bad_length() {
+        // A name that runs past its rdlength, and a TXT string longer than the rdata
+        let raw_rdata = b"\x04\x6d\x61\x69\x6c\x00";
+        assert_eq!(
+            RData::from_slice(raw_rdata, 0, 3, Some(TYPE_CNAME)),
+            Err(DnsParseError::BadRDataLength {
+                atype: TYPE_CNAME,
+                rdlength: 3
+            })
+        );
+        assert_eq!(
+            RData::from_slice(raw_rdata, 0, 3, Some(TYPE_TXT)),
+            Err(DnsParseError::BadRDataLength {
+                atype: TYPE_TXT,
+                rdlength: 3
+            })
+        );
+    }
+
+    #[test]
+    fn rdata_from_text() {
+        assert_eq!(
+            RData::from_text(TYPE_AAAA, "2606:4700::1111"),
+            Ok(RData::AAAARecord {
+                ip: "2606:4700::1111".parse().unwrap()
+            })
+        );
+        assert_eq!(
+            RData::from_text(
+                TYPE_SOA,
+                "ns.example.com. admin.example.com. 1 7200 900 1209600 300"
+            ),
+            Ok(RData::SOARecord {
+                mname: "ns.example.com".parse().unwrap(),
+                rname: "admin.example.com".parse().unwrap(),
+                serial: 1,
+                refresh: 7200,
+                retry: 900,
+                expire: 1209600,
+                minimum: 300,
+            })
+        );
+        assert_eq!(
+            RData::from_text(TYPE_SRV, "10 5 5060 sip.example.com."),
+            Ok(RData::SRVRecord {
+                priority: 10,
+                weight: 5,
+                port: 5060,
+                target: "sip.example.com".parse().unwrap(),
+            })
+        );
+        assert_eq!(
+            RData::from_text(TYPE_TXT, r#""a \"quoted\" \\ string" "\065""#),
+            Ok(RData::TXTRecord {
+                strings: vec![br#"a "quoted" \ string"#.to_vec(), b"A".to_vec()]
+            })
+        );
+        assert_eq!(
+            RData::from_text(TYPE_TXT, "unquoted text"),
+            Ok(RData::TXTRecord {
+                strings: vec![b"unquoted text".to_vec()]
+            })
+        );
+        assert_eq!(
+            RData::from_text(TYPE_TXT, &"a".repeat(300)),
+            Ok(RData::TXTRecord {
+                strings: vec![b"a".repeat(255), b"a".repeat(45)]
+            })
+        );
+        assert_eq!(
+            RData::from_text(65, "\\# 3 abcd ef"),
+            Ok(RData::Other {
+                data: vec![0xab, 0xcd, 0xef]
+            })
+        );
+
+        for (atype, text) in [
+            (TYPE_A, "1.2.3"),
+            (TYPE_MX, "10"),
+            (TYPE_TXT, r#""unterminated"#),
+            (65, "\\# 4 abcd"),
+            (65, "\\# 1 a"),
+            (65, "opaque"),
+        ] {
+            assert!(RData::from_text(atype, text).is_err(), "{text}");
+        }
+    }
+
     #[test]
     fn dnsanswersection_serialize_deserialize() {
         let raw_dns_answer = b"\xc0\x0c\x00\x1c\x00\x01\x00\x01\x51\x80\x00\x10\x26\x00\x3c\x01\x00\x00\x00\x00\xf0\x3c\x92\xff\xfe\xb3\x3c\x07";
-        let dns_answer_struct = DnsAnswerSection::from_slice(raw_dns_answer);
+        // The answer name points back at the question, so it is parsed in the context of a full message
+        let raw_dns_context = b"\x02\xaf\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\x06\x67\x69\x74\x68\x75\x62\x03\x63\x6f\x6d\x00\x00\x1c\x00\x01";
+        let raw_dns = [&raw_dns_context[..], &raw_dns_answer[..]].concat();
+        let dns_answer_struct =
+            DnsAnswerSection::from_slice(&raw_dns, raw_dns_context.len()).unwrap();
         let dns_answer_bytes = dns_answer_struct.0.bytes();
 
-        assert_eq!(raw_dns_answer, dns_answer_bytes.as_slice());
+        // Serialized on its own the name is written out in full
+        let expanded_dns_answer = [&raw_dns_context[12..24], &raw_dns_answer[2..]].concat();
+        assert_eq!(expanded_dns_answer, dns_answer_bytes);
     }
 
     #[test]
     fn dnsquestionsection_serialize_deserialize() {
         let raw_dns_question = b"\x06\x67\x69\x74\x68\x75\x62\x03\x63\x6f\x6d\x00\x00\x1c\x00\x01";
-        let dns_question_struct = DnsQuestionSection::from_slice(raw_dns_question);
+        let dns_question_struct = DnsQuestionSection::from_slice(raw_dns_question, 0).unwrap();
         let dns_question_bytes = dns_question_struct.0.bytes();
 
         assert_eq!(raw_dns_question, dns_question_bytes.as_slice());
@@ -602,7 +1716,7 @@ mod tests {
     #[test]
     fn dnsheader_serialize_deserialize() {
         let raw_dns_header = b"\x02\xaf\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00";
-        let dns_header_struct = DnsHeader::from_slice(raw_dns_header);
+        let dns_header_struct = DnsHeader::from_slice(raw_dns_header).unwrap();
         let dns_header_bytes = dns_header_struct.0.bytes();
 
         assert_eq!(raw_dns_header, dns_header_bytes.as_slice());
@@ -611,7 +1725,7 @@ mod tests {
     #[test]
     fn dnsquery_serialize_deserialize() {
         let raw_dns = b"\x2b\x25\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x03\x77\x77\x77\x07\x6e\x65\x74\x66\x6c\x69\x78\x03\x63\x6f\x6d\x00\x00\x1c\x00\x01";
-        let dns_struct = DnsPacket::from_slice(raw_dns);
+        let dns_struct = DnsPacket::from_slice(raw_dns).unwrap();
         let dns_bytes = dns_struct.bytes();
 
         assert_eq!(&raw_dns[0..], dns_bytes.as_slice());
@@ -640,9 +1754,368 @@ mod tests {
 \x01\x08\x70\x0f\x00\x00\x00\x00\x00\x00\x23\xa2\x22\x9f\xc0\x43\
 \x00\x1c\x00\x01\x00\x00\x00\x13\x00\x10\x26\x20\x01\x08\x70\x0f\
 \x00\x00\x00\x00\x00\x00\x34\x28\xd6\x48";
-        let dns_struct = DnsPacket::from_slice(raw_dns);
+        let dns_struct = DnsPacket::from_slice(raw_dns).unwrap();
         let dns_bytes = dns_struct.bytes();
 
         assert_eq!(&raw_dns[0..], dns_bytes.as_slice());
     }
+
+    #[test]
+    fn dnsresponse_constructed_is_compressed() {
+        let raw_dns = b"\x2b\x25\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x03\x77\x77\x77\x07\x6e\x65\x74\x66\x6c\x69\x78\x03\x63\x6f\x6d\x00\x00\x1c\x00\x01";
+        let request = DnsPacket::from_slice(raw_dns).unwrap();
+
+        let mut response = DnsPacket::new_response(&request, RCODE_NOERROR);
+        response.answer_section.push(DnsAnswerSection {
+            name: "WWW.netflix.com".parse().unwrap(),
+            atype: 28,
+            class: 1,
+            ttl: 19,
+            rdata: RData::AAAARecord {
+                ip: "2620:108:700f::340a:19a7".parse().unwrap(),
+            },
+        });
+        response.additional_section.push(DnsAnswerSection {
+            name: "ns.netflix.com".parse().unwrap(),
+            atype: 1,
+            class: 1,
+            ttl: 19,
+            rdata: RData::ARecord {
+                ip: "192.0.2.1".parse().unwrap(),
+            },
+        });
+
+        let expected_dns = b"\
+\x2b\x25\x81\x80\x00\x01\x00\x01\x00\x00\x00\x01\x03\x77\x77\x77\
+\x07\x6e\x65\x74\x66\x6c\x69\x78\x03\x63\x6f\x6d\x00\x00\x1c\x00\
+\x01\xc0\x0c\x00\x1c\x00\x01\x00\x00\x00\x13\x00\x10\x26\x20\x01\
+\x08\x70\x0f\x00\x00\x00\x00\x00\x00\x34\x0a\x19\xa7\x02\x6e\x73\
+\xc0\x10\x00\x01\x00\x01\x00\x00\x00\x13\x00\x04\xc0\x00\x02\x01";
+
+        assert_eq!(&expected_dns[0..], response.bytes().as_slice());
+    }
+
+    #[test]
+    fn dnsresponse_truncated_to_limit() {
+        let raw_dns = b"\x2b\x25\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x03\x77\x77\x77\x07\x6e\x65\x74\x66\x6c\x69\x78\x03\x63\x6f\x6d\x00\x00\x10\x00\x01";
+        let request = DnsPacket::from_slice(raw_dns).unwrap();
+
+        let mut response = DnsPacket::new_response(&request, RCODE_NOERROR);
+        for _i in 0..4 {
+            response.answer_section.push(DnsAnswerSection {
+                name: "www.netflix.com".parse().unwrap(),
+                atype: 16,
+                class: 1,
+                ttl: 60,
+                rdata: RData::Other {
+                    data: vec![0x7f; 128],
+                },
+            });
+        }
+
+        assert!(response.bytes().len() > DNS_UDP_MAX_LEN);
+        assert_eq!(response.bytes_truncated(1024), response.bytes());
+
+        let truncated = DnsPacket::from_slice(&response.bytes_truncated(DNS_UDP_MAX_LEN)).unwrap();
+        assert!(truncated.answer_section.is_empty());
+        assert_eq!(truncated.question_section, request.question_section);
+        assert_ne!(truncated.header.flags & FLAG_TRUNCATED, 0);
+    }
+
+    #[test]
+    fn dnspacket_is_response_to() {
+        let question = DnsQuestionSection {
+            qname: "www.example.com".parse().unwrap(),
+            qtype: TYPE_AAAA,
+            qclass: CLASS_IN,
+        };
+        let request = DnsPacket::new_with_questions(vec![question]);
+        let response = DnsPacket::new_response(&request, RCODE_NOERROR);
+        assert!(response.is_response_to(&request));
+        assert!(DnsPacket::new_response(&request, RCODE_SERVFAIL).is_response_to(&request));
+
+        // QR must be set
+        assert!(!request.is_response_to(&request));
+
+        let mut mismatched = response.clone();
+        mismatched.header.id = mismatched.header.id.wrapping_add(1);
+        assert!(!mismatched.is_response_to(&request));
+
+        // NOTIFY (4) rather than QUERY (0)
+        let mut mismatched = response.clone();
+        mismatched.header.flags |= 4 << 11;
+        assert_eq!(mismatched.header.opcode(), 4);
+        assert!(!mismatched.is_response_to(&request));
+
+        let mut mismatched = response.clone();
+        mismatched.question_section[0].qtype = TYPE_A;
+        assert!(!mismatched.is_response_to(&request));
+        let mut mismatched = response.clone();
+        mismatched.question_section[0].qname = "www.example.org".parse().unwrap();
+        assert!(!mismatched.is_response_to(&request));
+        let mut mismatched = response.clone();
+        mismatched.question_section.clear();
+        assert!(!mismatched.is_response_to(&request));
+    }
+
+    #[test]
+    fn edns_tcp_keepalive() {
+        let mut edns = Edns::new(false);
+        edns.request_tcp_keepalive();
+        assert_eq!(
+            edns.options,
+            vec![EdnsOption {
+                code: EDNS_OPTION_TCP_KEEPALIVE,
+                data: vec![],
+            }]
+        );
+        // Requests carry no timeout
+        assert_eq!(edns.tcp_keepalive(), None);
+
+        edns.options[0].data = vec![0x01, 0x2c];
+        assert_eq!(edns.tcp_keepalive(), Some(Duration::from_secs(30)));
+        edns.options[0].data = vec![0x00, 0x00];
+        assert_eq!(edns.tcp_keepalive(), Some(Duration::ZERO));
+
+        let mut packet = DnsPacket::new_with_questions(vec![]);
+        packet.edns = Some(edns.clone());
+        let packet = DnsPacket::from_slice(&packet.bytes()).unwrap();
+        assert_eq!(packet.edns, Some(edns));
+    }
+
+    #[test]
+    fn dnspacket_edns_serialize_deserialize() {
+        let raw_dns = b"\x2b\x25\x01\x00\x00\x01\x00\x00\x00\x00\x00\x01\x03\x77\x77\x77\x07\x6e\x65\x74\x66\x6c\x69\x78\x03\x63\x6f\x6d\x00\x00\x10\x00\x01\
+\x00\x00\x29\x10\x00\x00\x00\x80\x00\x00\x0c\x00\x0a\x00\x08\x01\x02\x03\x04\x05\x06\x07\x08";
+        let request = DnsPacket::from_slice(raw_dns).unwrap();
+
+        assert!(request.additional_section.is_empty());
+        assert_eq!(
+            request.edns,
+            Some(Edns {
+                udp_payload_size: 4096,
+                extended_rcode: 0,
+                version: 0,
+                dnssec_ok: true,
+                options: vec![EdnsOption {
+                    code: 10,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                }],
+            })
+        );
+        assert_eq!(&raw_dns[0..], request.bytes().as_slice());
+        assert_eq!(
+            request.max_udp_response_len(),
+            EDNS_UDP_PAYLOAD_SIZE as usize
+        );
+
+        // Responses advertise our own payload size, echo DO and keep the OPT record when truncated
+        let mut response = DnsPacket::new_response(&request, RCODE_NOERROR);
+        response.answer_section.push(DnsAnswerSection {
+            name: "www.netflix.com".parse().unwrap(),
+            atype: TYPE_TXT,
+            class: 1,
+            ttl: 60,
+            rdata: RData::Other {
+                data: vec![0x7f; 2048],
+            },
+        });
+
+        let truncated = DnsPacket::from_slice(&response.bytes_truncated(DNS_UDP_MAX_LEN)).unwrap();
+        assert!(truncated.answer_section.is_empty());
+        assert_eq!(truncated.edns, Some(Edns::new(true)));
+
+        let mut duplicate_opt = raw_dns.to_vec();
+        duplicate_opt[11] = 2;
+        duplicate_opt.extend_from_slice(&raw_dns[33..]);
+        assert_eq!(
+            DnsPacket::from_slice(&duplicate_opt),
+            Err(DnsParseError::DuplicateOpt)
+        );
+    }
+
+    #[test]
+    fn dnsname_compression_pointers() {
+        let mut raw_dns: Vec<u8> = b"\x2b\x25\x81\x80\x00\x00\x00\x04\x00\x00\x00\x00".to_vec();
+        // A padding record pushes the following names past offset 255
+        raw_dns.extend_from_slice(b"\x00\x00\x63\x00\x01\x00\x00\x00\x3c\x01\x00");
+        raw_dns.extend_from_slice(&[0; 256]);
+
+        let name_offset = raw_dns.len() as u16;
+        raw_dns.extend_from_slice(
+            b"\x03\x77\x77\x77\x07\x65\x78\x61\x6d\x70\x6c\x65\x03\x63\x6f\x6d\x00",
+        );
+        raw_dns.extend_from_slice(b"\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x01\x02\x03\x04");
+
+        // mail + pointer to the middle of www.example.com
+        let example_pointer = (0xc000 | (name_offset + 4)).to_be_bytes();
+        raw_dns.extend_from_slice(b"\x04\x6d\x61\x69\x6c");
+        raw_dns.extend_from_slice(&example_pointer);
+        raw_dns.extend_from_slice(b"\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x01\x02\x03\x05");
+
+        // CNAME with a pointer inside the RDATA
+        raw_dns.extend_from_slice(&(0xc000 | name_offset).to_be_bytes());
+        raw_dns.extend_from_slice(b"\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x06\x03\x63\x64\x6e");
+        raw_dns.extend_from_slice(&example_pointer);
+
+        let dns_struct = DnsPacket::from_slice(&raw_dns).unwrap();
+
+        assert_eq!(
+            dns_struct.answer_section[1].name_string(),
+            "www.example.com."
+        );
+        assert_eq!(
+            dns_struct.answer_section[2].name_string(),
+            "mail.example.com."
+        );
+        assert_eq!(
+            dns_struct.answer_section[3].name_string(),
+            "www.example.com."
+        );
+        assert_eq!(
+            dns_struct.answer_section[3].rdata,
+            RData::CNAMERecord {
+                name: "cdn.example.com".parse().unwrap()
+            }
+        );
+        assert_eq!(
+            DnsPacket::from_slice(&dns_struct.bytes()).unwrap(),
+            dns_struct
+        );
+    }
+
+    #[test]
+    fn dnsname_parse_display() {
+        let dns_name: DnsName = "WWW.Example.com".parse().unwrap();
+
+        assert_eq!(dns_name, "www.example.com.".parse().unwrap());
+        assert_eq!(dns_name.to_string(), "WWW.Example.com.");
+        assert_eq!(DnsName::root().to_string(), ".");
+        assert_eq!(".".parse::<DnsName>().unwrap(), DnsName::root());
+        assert!("www..com".parse::<DnsName>().is_err());
+
+        let parent: DnsName = "Example.com".parse().unwrap();
+        assert!(dns_name.is_subdomain_of(&parent));
+        assert!(parent.is_subdomain_of(&parent));
+        assert!(parent.is_subdomain_of(&DnsName::root()));
+        assert!(!parent.is_subdomain_of(&dns_name));
+        assert!(!"notexample.com"
+            .parse::<DnsName>()
+            .unwrap()
+            .is_subdomain_of(&parent));
+
+        let (dns_name, _) = DnsName::from_slice(b"\x05\x61\x2e\x62\x20\x63\x00", 0).unwrap();
+        assert_eq!(dns_name.to_string(), "a\\.b\\032c.");
+    }
+
+    #[test]
+    fn dnspacket_malformed_corpus() {
+        let corpus: [(&[u8], DnsParseError); 10] = [
+            (
+                b"\x2b\x25\x01\x00\x00\x01",
+                DnsParseError::Truncated {
+                    offset: 0,
+                    needed: 12,
+                },
+            ),
+            (
+                b"\x2b\x25\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07\x6e\x65\x74",
+                DnsParseError::Truncated {
+                    offset: 12,
+                    needed: 8,
+                },
+            ),
+            (
+                b"\x2b\x25\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x03\x77\x77\x77\x00",
+                DnsParseError::Truncated {
+                    offset: 17,
+                    needed: 2,
+                },
+            ),
+            (
+                b"\x2b\x25\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x43\x77\x77\x77\x00\x00\x01\x00\x01",
+                DnsParseError::BadLabelLength {
+                    offset: 12,
+                    length: 0x43,
+                },
+            ),
+            (
+                b"\x2b\x25\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\xc0\x0c\x00\x01\x00\x01",
+                DnsParseError::PointerLoop {
+                    offset: 12,
+                    target: 12,
+                },
+            ),
+            (
+                b"\x2b\x25\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x03\x77\x77\x77\xc0\x14\x00\x01\x00\x01",
+                DnsParseError::PointerLoop {
+                    offset: 16,
+                    target: 20,
+                },
+            ),
+            (
+                b"\x2b\x25\x01\x00\x00\x02\x00\x00\x00\x00\x00\x00\x03\x77\x77\x77\x00\x00\x01\x00\x01",
+                DnsParseError::CountMismatch {
+                    section: "question",
+                    expected: 2,
+                    found: 1,
+                },
+            ),
+            (
+                b"\x2b\x25\x81\x80\x00\x01\x00\x02\x00\x00\x00\x00\x03\x77\x77\x77\x00\x00\x01\x00\x01\
+\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x01\x02\x03\x04",
+                DnsParseError::CountMismatch {
+                    section: "answer",
+                    expected: 2,
+                    found: 1,
+                },
+            ),
+            (
+                b"\x2b\x25\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\x03\x77\x77\x77\x00\x00\x01\x00\x01\
+\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x05\x01\x02\x03\x04\x05",
+                DnsParseError::BadRDataLength {
+                    atype: 1,
+                    rdlength: 5,
+                },
+            ),
+            (
+                b"\x2b\x25\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\x03\x77\x77\x77\x00\x00\x01\x00\x01\
+\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x01\x02",
+                DnsParseError::Truncated {
+                    offset: 33,
+                    needed: 4,
+                },
+            ),
+        ];
+
+        for (raw_dns, expected_error) in corpus {
+            assert_eq!(DnsPacket::from_slice(raw_dns), Err(expected_error));
+        }
+    }
+
+    #[test]
+    fn dnspacket_truncated_or_mutated_does_not_panic() {
+        let raw_dns = b"\
+\x02\xaf\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\x06\x67\x69\x74\
+\x68\x75\x62\x03\x63\x6f\x6d\x00\x00\x1c\x00\x01\xc0\x0c\x00\x1c\
+\x00\x01\x00\x01\x51\x80\x00\x10\x26\x00\x3c\x01\x00\x00\x00\x00\
+\xf0\x3c\x92\xff\xfe\xb3\x3c\x07";
+        assert!(DnsPacket::from_slice(raw_dns).is_ok());
+
+        for len in 0..raw_dns.len() {
+            assert!(DnsPacket::from_slice(&raw_dns[..len]).is_err());
+        }
+
+        for index in 0..raw_dns.len() {
+            for value in [0x00, 0x01, 0x3f, 0x40, 0x80, 0xc0, 0xc1, 0xff] {
+                let mut mutated_dns = raw_dns.to_vec();
+                mutated_dns[index] = value;
+
+                if let Ok(dns_struct) = DnsPacket::from_slice(&mutated_dns) {
+                    let _ = dns_struct.to_string();
+                    let _ = dns_struct.bytes();
+                }
+            }
+        }
+    }
 }
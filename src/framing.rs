@@ -0,0 +1,201 @@
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
+
+use byteorder::{ByteOrder, NetworkEndian};
+
+use crate::dns;
+
+pub const STDIO_ADDR: &str = "-";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StdioFormat {
+    Wire,
+    Hex,
+    Text,
+}
+
+impl StdioFormat {
+    pub fn read_message<R: BufRead>(&self, reader: &mut R) -> io::Result<Vec<u8>> {
+        match self {
+            StdioFormat::Wire => read_length_prefixed(reader),
+            StdioFormat::Hex => read_hex_line(reader),
+            StdioFormat::Text => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Messages in the text format cannot be read back",
+            )),
+        }
+    }
+
+    pub fn write_message<W: Write>(
+        &self,
+        writer: &mut W,
+        message: &dns::DnsPacket,
+    ) -> io::Result<()> {
+        match self {
+            StdioFormat::Wire => write_length_prefixed(writer, &message.bytes())?,
+            StdioFormat::Hex => write_hex_line(writer, &message.bytes())?,
+            StdioFormat::Text => writeln!(writer, "{message}")?,
+        }
+        writer.flush()
+    }
+
+    pub fn is_readable(&self) -> bool {
+        *self != StdioFormat::Text
+    }
+}
+
+impl FromStr for StdioFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "wire" => Ok(StdioFormat::Wire),
+            "hex" => Ok(StdioFormat::Hex),
+            "text" => Ok(StdioFormat::Text),
+            _ => Err(format!(
+                "Unknown format `{format}`, expected `wire`, `hex` or `text`"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StdioFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StdioFormat::Wire => write!(f, "wire"),
+            StdioFormat::Hex => write!(f, "hex"),
+            StdioFormat::Text => write!(f, "text"),
+        }
+    }
+}
+
+/// Reads one message framed with a 2 byte length prefix, as used by DNS over TCP (RFC 1035 4.2.2).
+pub fn read_length_prefixed<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut len_buf: [u8; 2] = [0; 2];
+    reader.read_exact(&mut len_buf)?;
+    let len = NetworkEndian::read_u16(&len_buf);
+
+    let mut message: Vec<u8> = vec![0u8; len.into()];
+    reader.read_exact(&mut message)?;
+
+    Ok(message)
+}
+
+pub fn write_length_prefixed<W: Write>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+    let len: u16 = message.len().try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Message of {} bytes is too long to frame", message.len()),
+        )
+    })?;
+
+    // Written with a single call so the prefix and message share a TCP segment / TLS record
+    let mut payload: Vec<u8> = Vec::with_capacity(message.len() + 2);
+    let mut u16buf = [0; 2];
+    NetworkEndian::write_u16(&mut u16buf, len);
+    payload.extend_from_slice(&u16buf);
+    payload.extend_from_slice(message);
+
+    writer.write_all(&payload)
+}
+
+/// Reads one message written as a line of hex digits. Blank lines and `#` comments are skipped.
+pub fn read_hex_line<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        return decode_hex(line);
+    }
+}
+
+pub fn write_hex_line<W: Write>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+    let mut line: String = message.iter().map(|byte| format!("{byte:02x}")).collect();
+    line.push('\n');
+
+    writer.write_all(line.as_bytes())
+}
+
+fn decode_hex(hex: &str) -> io::Result<Vec<u8>> {
+    let digits: Vec<u8> = hex
+        .bytes()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+
+    if !digits.len().is_multiple_of(2) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Hex message has an odd number of digits",
+        ));
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid hex digits `{}`", String::from_utf8_lossy(pair)),
+                    )
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_prefixed_write_read() {
+        let message = b"\x2b\x25\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00";
+        let mut framed: Vec<u8> = Vec::new();
+        write_length_prefixed(&mut framed, message).unwrap();
+
+        assert_eq!(&framed[0..2], b"\x00\x0c");
+        assert_eq!(
+            read_length_prefixed(&mut framed.as_slice()).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn hex_line_write_read() {
+        let message = b"\x2b\x25\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00";
+        let mut line: Vec<u8> = Vec::new();
+        write_hex_line(&mut line, message).unwrap();
+
+        assert_eq!(line, b"2b2501000001000000000000\n");
+        assert_eq!(read_hex_line(&mut line.as_slice()).unwrap(), message);
+    }
+
+    #[test]
+    fn hex_line_skips_comments_and_whitespace() {
+        let mut input: &[u8] = b"# netflix AAAA\n\n2b25 0100 0001\n\n";
+        let message = read_hex_line(&mut input).unwrap();
+
+        assert_eq!(message, b"\x2b\x25\x01\x00\x00\x01");
+        assert_eq!(
+            read_hex_line(&mut input).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn hex_line_rejects_invalid_digits() {
+        let error = read_hex_line(&mut b"2b2x\n".as_slice()).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,412 @@
+use std::io::{self, BufRead, Read, Write};
+use std::sync::Arc;
+use std::thread;
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const MAX_LINE_LEN: u64 = 8192;
+const MAX_HEADERS: usize = 64;
+const MAX_BODY_LEN: usize = 65535;
+const H2_BUFFER_LEN: usize = 16384;
+
+/// Status, headers and body of a response.
+pub type HttpResponse = (u16, Vec<(&'static str, String)>, Vec<u8>);
+
+/// The parts of an HTTP request needed to serve DNS over HTTPS (RFC 8484).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+fn invalid_data<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line: Vec<u8> = Vec::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+
+    if line.is_empty() {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    if !line.ends_with(b"\n") {
+        return Err(invalid_data("HTTP line is too long or unterminated"));
+    }
+
+    let line = String::from_utf8(line).map_err(|_| invalid_data("HTTP line is not UTF-8"))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Reads one request, including its body when a `Content-Length` is given.
+/// Reaching the end of the stream before a request starts is reported as `UnexpectedEof`.
+pub fn read_request<R: BufRead>(reader: &mut R) -> io::Result<HttpRequest> {
+    // Empty lines before the request line are ignored (RFC 9112 2.2)
+    let mut request_line = read_line(reader)?;
+    while request_line.is_empty() {
+        request_line = read_line(reader)?;
+    }
+
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    let [method, target, version] = parts[..] else {
+        return Err(invalid_data(format!("Bad request line `{request_line}`")));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid_data(format!(
+            "Unsupported HTTP version `{version}`"
+        )));
+    }
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(invalid_data("Too many HTTP headers"));
+        }
+
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_data(format!("Bad header line `{line}`")))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+        body: vec![],
+    };
+
+    if request.header("transfer-encoding").is_some() {
+        return Err(invalid_data("Chunked request bodies are not supported"));
+    }
+
+    if let Some(content_length) = request.header("content-length") {
+        let content_length: usize = content_length
+            .parse()
+            .map_err(|_| invalid_data(format!("Bad Content-Length `{content_length}`")))?;
+        if content_length > MAX_BODY_LEN {
+            return Err(invalid_data(format!(
+                "Request body of {content_length} bytes is too long"
+            )));
+        }
+
+        request.body = vec![0u8; content_length];
+        reader.read_exact(&mut request.body)?;
+    }
+
+    Ok(request)
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.target,
+        }
+    }
+
+    /// The percent-decoded value of a query parameter. Parameters with bad escapes are skipped.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        let (_, query) = self.target.split_once('?')?;
+        query
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .filter_map(|(param_name, value)| Some((percent_decode(param_name)?, value)))
+            .find(|(param_name, _)| param_name == name)
+            .and_then(|(_, value)| percent_decode(value))
+    }
+
+    /// Whether the connection stays open after the response, per the version and `Connection`.
+    pub fn keep_alive(&self) -> bool {
+        match self.header("connection") {
+            Some(connection) if connection.eq_ignore_ascii_case("close") => false,
+            Some(connection) if connection.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+}
+
+/// Decodes `%XX` escapes, and `+` as a space as in HTML forms.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut decoded: Vec<u8> = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' => decoded.push(b' '),
+            _ => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        415 => "Unsupported Media Type",
+        _ => "Unknown",
+    }
+}
+
+pub fn write_response<W: Write>(
+    writer: &mut W,
+    status: u16,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> io::Result<()> {
+    let mut response = format!("HTTP/1.1 {status} {}\r\n", reason_phrase(status));
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+
+    // Written with a single call so small responses share a TCP segment / TLS record
+    let mut payload = response.into_bytes();
+    payload.extend_from_slice(body);
+
+    writer.write_all(&payload)?;
+    writer.flush()
+}
+
+/// Serves an HTTP/2 connection over a blocking `reader` and `writer`, until the client closes it
+/// or the reader fails. Requests are answered concurrently by `handler` on blocking threads.
+pub fn serve_h2<R, W, H>(mut reader: R, mut writer: W, handler: H) -> io::Result<()>
+where
+    R: Read + Send,
+    W: Write + Send,
+    H: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+{
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    let handle = runtime.handle();
+    // The h2 connection runs on one end, and threads copy between the other end and the stream
+    let (connection_io, bridge_io) = tokio::io::duplex(H2_BUFFER_LEN);
+    let (mut bridge_reader, mut bridge_writer) = tokio::io::split(bridge_io);
+    let handler = Arc::new(handler);
+
+    thread::scope(|scope| {
+        let reading = scope.spawn(move || -> io::Result<()> {
+            let mut buffer = [0u8; H2_BUFFER_LEN];
+            let result = loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) => break Ok(()),
+                    Ok(len) => {
+                        if handle
+                            .block_on(bridge_writer.write_all(&buffer[..len]))
+                            .is_err()
+                        {
+                            break Ok(());
+                        }
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                    Err(error) => break Err(error),
+                }
+            };
+            let _ = handle.block_on(bridge_writer.shutdown());
+            result
+        });
+        let writing = scope.spawn(move || -> io::Result<()> {
+            let mut buffer = [0u8; H2_BUFFER_LEN];
+            loop {
+                match handle.block_on(bridge_reader.read(&mut buffer))? {
+                    0 => return Ok(()),
+                    len => {
+                        writer.write_all(&buffer[..len])?;
+                        writer.flush()?;
+                    }
+                }
+            }
+        });
+
+        let served = runtime.block_on(async move {
+            let mut connection = h2::server::handshake(connection_io).await?;
+            while let Some(result) = connection.accept().await {
+                let (request, respond) = result?;
+                tokio::spawn(respond_h2(request, respond, Arc::clone(&handler)));
+            }
+            Ok::<(), h2::Error>(())
+        });
+        // Dropping the connection lets the writing thread finish
+        let written = writing.join().expect("HTTP/2 writer panicked");
+        let read = reading.join().expect("HTTP/2 reader panicked");
+
+        read.and(written)
+            .and(served.map_err(|error| match error.into_io() {
+                Some(error) => error,
+                None => io::Error::other("HTTP/2 protocol error"),
+            }))
+    })
+}
+
+async fn respond_h2<H>(
+    request: ::http::Request<h2::RecvStream>,
+    mut respond: h2::server::SendResponse<Bytes>,
+    handler: Arc<H>,
+) where
+    H: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+{
+    let (parts, mut body_stream) = request.into_parts();
+
+    let mut body: Vec<u8> = Vec::new();
+    while let Some(chunk) = body_stream.data().await {
+        let Ok(chunk) = chunk else {
+            return;
+        };
+        let _ = body_stream.flow_control().release_capacity(chunk.len());
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_BODY_LEN {
+            respond.send_reset(h2::Reason::REFUSED_STREAM);
+            return;
+        }
+    }
+
+    let http_request = HttpRequest {
+        method: parts.method.to_string(),
+        target: parts
+            .uri
+            .path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str())
+            .to_string(),
+        version: "HTTP/2".to_string(),
+        headers: parts
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        body,
+    };
+    let Ok((status, headers, body)) =
+        tokio::task::spawn_blocking(move || handler(&http_request)).await
+    else {
+        respond.send_reset(h2::Reason::INTERNAL_ERROR);
+        return;
+    };
+
+    let mut response = ::http::Response::builder().status(status);
+    for (name, value) in headers {
+        response = response.header(name, value);
+    }
+    let response = response
+        .header("content-length", body.len())
+        .body(())
+        .expect("Response headers should be valid");
+
+    let Ok(mut send_stream) = respond.send_response(response, body.is_empty()) else {
+        return;
+    };
+    if !body.is_empty() {
+        let _ = send_stream.send_data(Bytes::from(body), true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_get_with_query() {
+        let mut input: &[u8] =
+            b"GET /dns-query?ct&dns=q80BAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB HTTP/1.1\r\nHost: localhost\r\nAccept: application/dns-message\r\n\r\n";
+        let request = read_request(&mut input).unwrap();
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path(), "/dns-query");
+        assert_eq!(
+            request.query_param("dns").as_deref(),
+            Some("q80BAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB")
+        );
+        assert_eq!(request.query_param("ct"), None);
+        assert_eq!(request.header("ACCEPT"), Some("application/dns-message"));
+        assert!(request.keep_alive());
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn request_query_param_percent_decoded() {
+        let mut input: &[u8] =
+            b"GET /dns-query?dns=q80BAA%3d%3D&na%6De=a%2Bb+c%20d&bad=%zz&cut=%4 HTTP/1.1\r\n\r\n";
+        let request = read_request(&mut input).unwrap();
+
+        assert_eq!(request.query_param("dns").as_deref(), Some("q80BAA=="));
+        assert_eq!(request.query_param("name").as_deref(), Some("a+b c d"));
+        assert_eq!(request.query_param("bad"), None);
+        assert_eq!(request.query_param("cut"), None);
+    }
+
+    #[test]
+    fn request_post_pipelined() {
+        let mut input: &[u8] = b"POST /dns-query HTTP/1.1\r\ncontent-type: application/dns-message\r\nContent-Length: 4\r\n\r\n\x2b\x25\x01\x00\
+\r\nPOST /dns-query HTTP/1.0\r\nContent-Length: 0\r\n\r\n";
+        let request = read_request(&mut input).unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(
+            request.header("Content-Type"),
+            Some("application/dns-message")
+        );
+        assert_eq!(request.body, b"\x2b\x25\x01\x00");
+
+        let request = read_request(&mut input).unwrap();
+        assert!(!request.keep_alive());
+        assert_eq!(
+            read_request(&mut input).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn request_rejects_malformed() {
+        let corpus: [&[u8]; 4] = [
+            b"GET /dns-query\r\n\r\n",
+            b"GET /dns-query HTTP/2\r\n\r\n",
+            b"POST /dns-query HTTP/1.1\r\nContent-Length: 100000\r\n\r\n",
+            b"POST /dns-query HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ];
+
+        for mut input in corpus {
+            assert_eq!(
+                read_request(&mut input).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn response_write() {
+        let mut output: Vec<u8> = Vec::new();
+        write_response(
+            &mut output,
+            200,
+            &[("Content-Type", "application/dns-message".to_string())],
+            b"\x2b\x25",
+        )
+        .unwrap();
+
+        assert_eq!(
+            output,
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: 2\r\n\r\n\x2b\x25"
+        );
+    }
+}
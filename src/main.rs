@@ -1,43 +1,186 @@
-use clap::{arg, command};
+use clap::error::ErrorKind;
+use clap::{arg, command, ArgAction};
 use simplelog::*;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 mod cache;
 mod dest;
 mod dns;
+mod framing;
+mod http;
+mod pool;
 mod source;
+mod tls;
 
 fn main() {
-    let matches = command!()
+    let mut command = command!()
         .arg(arg!(-v --verbose "Print verbose output"))
         .arg(arg!(-c --cache "Enable the prefetch cache"))
-        .arg(arg!(-s --source <SOURCE> "Source for the requests. Using \"-\" inputs from stdin. See README for detailed usage.").required(true))
-        .arg(arg!(-d --dest <DEST> "Destination for the requests. Using \"-\" outputs to stdout. See README for detailed usage.").required(true))
-        .get_matches();
+        .arg(arg!(-s --source <SOURCE> "Source for the requests. Using \"-\" inputs from stdin. See README for detailed usage.").required(true).value_parser(clap::value_parser!(source::SourceSpec)))
+        .arg(arg!(-d --dest <DEST> "Destination for the requests. Using \"-\" outputs to stdout. Repeat to fail over to further destinations in order. See README for detailed usage.").required(true).action(ArgAction::Append).value_parser(clap::value_parser!(dest::DestSpec)))
+        .arg(arg!(--"tls-cert" <FILE> "PEM certificate chain served when the source is \"tls://\" or \"https://\".").requires("tls-key"))
+        .arg(arg!(--"tls-key" <FILE> "PEM private key for --tls-cert.").requires("tls-cert"))
+        .arg(arg!(--forward <RULE> "Send queries for a domain and its subdomains to another destination, as SUFFIX=DEST, eg. \"*.corp.internal=10.0.0.53\". Can be repeated; the longest matching suffix wins.").action(ArgAction::Append).value_parser(clap::value_parser!(dest::ForwardRule)))
+        .arg(arg!(--strategy <STRATEGY> "How queries pick among several destinations: \"failover\" (in order), \"round-robin\", \"random\" (weighted by --weights), \"fastest\" (lowest smoothed RTT) or \"race\" (all at once).").default_value("failover").value_parser(clap::value_parser!(dest::Strategy)))
+        .arg(arg!(--weights <WEIGHTS> "Comma separated weight of each destination for the random strategy. Defaults to equal weights.").value_delimiter(',').value_parser(clap::value_parser!(u32).range(1..)))
+        .arg(arg!(--timeout <MILLIS> "How long each attempt at a destination waits for an answer.").default_value("2000").value_parser(clap::value_parser!(u64).range(1..)))
+        .arg(arg!(--retries <COUNT> "How many times a failed query is retried at a destination, with exponential backoff, before moving on.").default_value("1").value_parser(clap::value_parser!(u16)))
+        .arg(arg!(--"tcp-keepalive" "Ask DoT destinations how long they keep idle connections open (EDNS TCP keepalive), and reconnect before then."))
+        .arg(arg!(--"dot-ca" <FILE> "PEM file of extra CA certificates trusted for DoT destinations. Can be repeated.").action(ArgAction::Append))
+        .arg(arg!(--"dot-pin" <SHA256> "Base64 SHA-256 hash of a public key (SPKI) that DoT destinations' certificate chains must contain. Repeat to allow several keys. Checked on top of the usual CA validation, so a self-signed destination also needs its certificate given with --dot-ca.").action(ArgAction::Append).value_parser(clap::value_parser!(tls::SpkiPin)))
+        .arg(arg!(--"dot-client-cert" <FILE> "PEM certificate chain presented to DoT destinations for mutual TLS.").requires("dot-client-key"))
+        .arg(arg!(--"dot-client-key" <FILE> "PEM private key for --dot-client-cert.").requires("dot-client-cert"))
+        .arg(arg!(--"doh-method" <METHOD> "How queries are sent to DoH destinations: \"post\" or \"get\" (cacheable by HTTP caches).").default_value("post").value_parser(clap::value_parser!(dest::DohMethod)))
+        .arg(arg!(--"doh-http" <VERSION> "HTTP version spoken to DoH destinations: \"auto\" (HTTP/2 when offered), \"1.1\" or \"2\".").default_value("auto").value_parser(clap::value_parser!(dest::HttpVersion)))
+        .arg(arg!(--bootstrap <IP_ADDR> "Plain DNS server used to resolve the hostnames of DoH destinations, instead of the system resolver.").value_parser(dest::parse_dns_server))
+        .arg(arg!(-w --workers <COUNT> "Number of threads answering UDP and TCP requests.").default_value("16").value_parser(clap::value_parser!(u16).range(1..)))
+        .arg(arg!(--"queue-depth" <COUNT> "Number of UDP requests waiting for a worker before new ones are dropped.").default_value("128").value_parser(clap::value_parser!(u16).range(1..)))
+        .arg(arg!(-f --format <FORMAT> "Format used when reading from stdin or writing to stdout: \"wire\" (length prefixed), \"hex\" (one message per line) or \"text\" (human readable, stdout only).").default_value("wire").value_parser(clap::value_parser!(framing::StdioFormat)));
+    let matches = command.get_matches_mut();
 
     let log_level = match matches.get_flag("verbose") {
         true => LevelFilter::Debug,
         _ => LevelFilter::Info,
     };
 
+    let source_spec = matches
+        .get_one::<source::SourceSpec>("source")
+        .expect("Argument should be required")
+        .clone();
+    let dest_specs: Vec<dest::DestSpec> = matches
+        .get_many::<dest::DestSpec>("dest")
+        .expect("Argument should be required")
+        .cloned()
+        .collect();
+    let forward_rules: Vec<dest::ForwardRule> = matches
+        .get_many::<dest::ForwardRule>("forward")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let strategy = *matches
+        .get_one::<dest::Strategy>("strategy")
+        .expect("Argument has a default");
+    let weights: Vec<u32> = match matches.get_many::<u32>("weights") {
+        Some(weights) => weights.copied().collect(),
+        None => vec![1; dest_specs.len()],
+    };
+    let dest_options = dest::DestOptions {
+        timeout: Duration::from_millis(
+            *matches
+                .get_one::<u64>("timeout")
+                .expect("Argument has a default"),
+        ),
+        retries: (*matches
+            .get_one::<u16>("retries")
+            .expect("Argument has a default"))
+        .into(),
+        keepalive: matches.get_flag("tcp-keepalive"),
+        tls_config: tls::client_config(
+            &matches
+                .get_many::<String>("dot-ca")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<String>>(),
+            &matches
+                .get_many::<tls::SpkiPin>("dot-pin")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<tls::SpkiPin>>(),
+            matches
+                .get_one::<String>("dot-client-cert")
+                .zip(matches.get_one::<String>("dot-client-key"))
+                .map(|(cert_path, key_path)| (cert_path.as_str(), key_path.as_str())),
+        ),
+        doh_method: *matches
+            .get_one::<dest::DohMethod>("doh-method")
+            .expect("Argument has a default"),
+        doh_http_version: *matches
+            .get_one::<dest::HttpVersion>("doh-http")
+            .expect("Argument has a default"),
+        bootstrap: matches.get_one::<SocketAddr>("bootstrap").copied(),
+    };
+    let stdio_format = *matches
+        .get_one::<framing::StdioFormat>("format")
+        .expect("Argument has a default");
+
+    // Keep stdout clean for DNS messages when it is used as a source or destination
+    let stdio_dest = dest_specs
+        .iter()
+        .chain(forward_rules.iter().map(|rule| &rule.dest))
+        .any(|dest_spec| *dest_spec == dest::DestSpec::Stdio);
+    // Both would read from stdin, each taking messages meant for the other
+    if source_spec == source::SourceSpec::Stdio && stdio_dest {
+        command
+            .error(
+                ErrorKind::ArgumentConflict,
+                "the source and a destination cannot both be \"-\"",
+            )
+            .exit();
+    }
+    if weights.len() != dest_specs.len() {
+        command
+            .error(
+                ErrorKind::WrongNumberOfValues,
+                format!(
+                    "got {} weight(s) for {} destination(s), --weights needs one per --dest",
+                    weights.len(),
+                    dest_specs.len()
+                ),
+            )
+            .exit();
+    }
+    if source_spec.needs_tls() && !matches.contains_id("tls-cert") {
+        command
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                format!("serving `{source_spec}` requires --tls-cert and --tls-key"),
+            )
+            .exit();
+    }
+    let terminal_mode = match source_spec == source::SourceSpec::Stdio || stdio_dest {
+        true => TerminalMode::Stderr,
+        _ => TerminalMode::Mixed,
+    };
+
     CombinedLogger::init(vec![TermLogger::new(
         log_level,
         Config::default(),
-        TerminalMode::Mixed,
+        terminal_mode,
         ColorChoice::Auto,
     )])
     .expect("Failed to initialize logger(s)");
 
-    let source_addr = matches
-        .get_one::<String>("source")
-        .expect("Argument should be required");
-    let dest_addr = matches
-        .get_one::<String>("dest")
-        .expect("Argument should be required");
+    let workers = *matches
+        .get_one::<u16>("workers")
+        .expect("Argument has a default");
+    let queue_depth = *matches
+        .get_one::<u16>("queue-depth")
+        .expect("Argument has a default");
+
+    let tls_config = matches
+        .get_one::<String>("tls-cert")
+        .zip(matches.get_one::<String>("tls-key"))
+        .map(|(cert_path, key_path)| tls::server_config(cert_path, key_path));
 
-    let dest = dest::DestClient::new(dest_addr);
+    let dest = dest::DestClient::new(
+        &dest_specs,
+        &weights,
+        &forward_rules,
+        strategy,
+        dest_options,
+        stdio_format,
+    );
     let cache = cache::DnsCache::new();
     let cache_manager = cache::DnsCacheManager::new(cache, dest);
-    let mut source = source::SourceServer::new(source_addr, cache_manager);
+    let mut source = source::SourceServer::new(
+        source_spec,
+        stdio_format,
+        tls_config,
+        workers.into(),
+        queue_depth.into(),
+        cache_manager,
+    );
 
     source.start()
 }
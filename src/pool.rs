@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use log::*;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct PoolState {
+    jobs: VecDeque<Job>,
+    threads: usize,
+    /// Threads waiting for a job, or about to wait for one
+    idle: usize,
+}
+
+/// Runs jobs on at most `max_threads` threads, started as they are first needed and then kept
+/// for later jobs. At most `max_queued` jobs wait for a thread.
+pub struct WorkerPool {
+    max_threads: usize,
+    max_queued: usize,
+    state: Mutex<PoolState>,
+    job_queued: Condvar,
+    /// Signalled whenever there may be room for another job
+    job_taken: Condvar,
+}
+
+impl WorkerPool {
+    pub fn new(max_threads: usize, max_queued: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max_threads,
+            max_queued,
+            state: Mutex::new(PoolState {
+                jobs: VecDeque::new(),
+                threads: 0,
+                idle: 0,
+            }),
+            job_queued: Condvar::new(),
+            job_taken: Condvar::new(),
+        })
+    }
+
+    fn has_room(&self, state: &PoolState) -> bool {
+        state.jobs.len() < state.idle + (self.max_threads - state.threads) + self.max_queued
+    }
+
+    /// Runs `job` once a thread is free, waiting while the queue is full.
+    pub fn execute<F: FnOnce() + Send + 'static>(self: &Arc<Self>, job: F) {
+        let mut state = self.state.lock().unwrap();
+        while !self.has_room(&state) {
+            state = self.job_taken.wait(state).unwrap();
+        }
+        self.push(state, Box::new(job));
+    }
+
+    /// Runs `job` once a thread is free, or gives it back when the queue is full.
+    pub fn try_execute<F: FnOnce() + Send + 'static>(self: &Arc<Self>, job: F) -> Result<(), F> {
+        let state = self.state.lock().unwrap();
+        if !self.has_room(&state) {
+            return Err(job);
+        }
+        self.push(state, Box::new(job));
+        Ok(())
+    }
+
+    fn push(self: &Arc<Self>, mut state: std::sync::MutexGuard<'_, PoolState>, job: Job) {
+        state.jobs.push_back(job);
+        if state.jobs.len() > state.idle && state.threads < self.max_threads {
+            state.threads += 1;
+            state.idle += 1;
+            let pool = Arc::clone(self);
+            thread::spawn(move || pool.work());
+        }
+        self.job_queued.notify_one();
+    }
+
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let job = match state.jobs.pop_front() {
+                Some(job) => job,
+                None => {
+                    state = self.job_queued.wait(state).unwrap();
+                    continue;
+                }
+            };
+            state.idle -= 1;
+            drop(state);
+            self.job_taken.notify_all();
+
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                error!("Worker job panicked");
+            }
+
+            state = self.state.lock().unwrap();
+            state.idle += 1;
+            self.job_taken.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::Barrier;
+
+    #[test]
+    fn runs_jobs_concurrently_and_sheds_overflow() {
+        let pool = WorkerPool::new(2, 1);
+        let started = Arc::new(Barrier::new(3));
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+        let release_receiver = Arc::new(Mutex::new(release_receiver));
+        let (done_sender, done_receiver) = mpsc::channel();
+
+        for job_index in 0..2 {
+            let started = Arc::clone(&started);
+            let release_receiver = Arc::clone(&release_receiver);
+            let done_sender = done_sender.clone();
+            pool.execute(move || {
+                started.wait();
+                release_receiver.lock().unwrap().recv().unwrap();
+                done_sender.send(job_index).unwrap();
+            });
+        }
+        // Both jobs run at once, or this would never return
+        started.wait();
+
+        let done_sender_queued = done_sender.clone();
+        assert!(pool
+            .try_execute(move || done_sender_queued.send(2).unwrap())
+            .is_ok());
+        assert!(pool.try_execute(|| {}).is_err());
+
+        release_sender.send(()).unwrap();
+        release_sender.send(()).unwrap();
+        let mut done: Vec<usize> = done_receiver.iter().take(3).collect();
+        done.sort();
+        assert_eq!(done, vec![0, 1, 2]);
+    }
+}
@@ -1,49 +1,976 @@
-use std::net::UdpSocket;
+use std::fmt;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use log::*;
 use retry::delay::Fixed;
 use retry::*;
 
 use crate::cache::DnsCacheManager;
+use crate::dest::{parse_socket_addr, DEFAULT_DNS_PORT, DEFAULT_DOT_PORT};
 use crate::dns;
+use crate::framing::{self, StdioFormat, STDIO_ADDR};
+use crate::http;
+use crate::pool::WorkerPool;
+
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections accepted beyond this are closed straight away
+const MAX_TCP_CONNECTIONS: usize = 256;
+const TLS_READ_BUFFER_LEN: usize = 16384;
+
+const DOT_ALPN_PROTOCOLS: &[&[u8]] = &[b"dot"];
+
+const DEFAULT_HTTPS_PORT: u16 = 443;
+const DEFAULT_HTTP_PORT: u16 = 80;
+const HTTP_ALPN_PROTOCOLS: &[&[u8]] = &[b"h2", b"http/1.1"];
+const DEFAULT_DOH_PATH: &str = "/dns-query";
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+
+/// A parsed `--source` address.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SourceSpec {
+    /// `-`
+    Stdio,
+    /// `ADDR`, serving both UDP and TCP
+    Dns(SocketAddr),
+    /// `tls://ADDR`
+    Tls(SocketAddr),
+    /// `https://ADDR[/PATH]`, or `http://ADDR[/PATH]` without TLS
+    Https {
+        addr: SocketAddr,
+        path: String,
+        tls: bool,
+    },
+}
+
+impl SourceSpec {
+    /// Whether serving needs a certificate and key.
+    pub fn needs_tls(&self) -> bool {
+        matches!(
+            self,
+            SourceSpec::Tls(_) | SourceSpec::Https { tls: true, .. }
+        )
+    }
+
+    fn parse_https(addr: &str, default_port: u16, tls: bool) -> Result<Self, String> {
+        let (addr, path) = match addr.find('/') {
+            Some(index) => addr.split_at(index),
+            None => (addr, DEFAULT_DOH_PATH),
+        };
+
+        Ok(SourceSpec::Https {
+            addr: parse_socket_addr(addr, default_port)?,
+            path: path.to_string(),
+            tls,
+        })
+    }
+}
+
+impl FromStr for SourceSpec {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let result = match source.split_once("://") {
+            Some(("tls", addr)) => parse_socket_addr(addr, DEFAULT_DOT_PORT).map(SourceSpec::Tls),
+            Some(("https", addr)) => SourceSpec::parse_https(addr, DEFAULT_HTTPS_PORT, true),
+            Some(("http", addr)) => SourceSpec::parse_https(addr, DEFAULT_HTTP_PORT, false),
+            Some((scheme, _)) => Err(format!(
+                "Unknown scheme `{scheme}`, expected `tls`, `https` or `http`"
+            )),
+            None if source == STDIO_ADDR => Ok(SourceSpec::Stdio),
+            None => parse_socket_addr(source, DEFAULT_DNS_PORT).map(SourceSpec::Dns),
+        };
+
+        result.map_err(|error| format!("Bad source `{source}`: {error}"))
+    }
+}
+
+impl fmt::Display for SourceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceSpec::Stdio => write!(f, "{STDIO_ADDR}"),
+            SourceSpec::Dns(addr) => write!(f, "{addr}"),
+            SourceSpec::Tls(addr) => write!(f, "tls://{addr}"),
+            SourceSpec::Https { addr, path, tls } => {
+                let scheme = if *tls { "https" } else { "http" };
+                write!(f, "{scheme}://{addr}{path}")
+            }
+        }
+    }
+}
+
+/// What is spoken over an accepted TCP or TLS connection.
+#[derive(Clone)]
+enum StreamProtocol {
+    Dns,
+    Http { path: String },
+}
+
+/// What the handlers of a TCP, TLS or HTTP listener's connections share.
+#[derive(Clone)]
+struct StreamContext {
+    cache: Arc<DnsCacheManager>,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    protocol: StreamProtocol,
+    idle_timeout: Duration,
+    /// Answers the queries pipelined on every connection, so a slow one holds up no others
+    query_pool: Arc<WorkerPool>,
+}
+
+/// A server TLS connection read by one thread while others write answers to it. Reads wait on
+/// the socket without holding the TLS state, as DoT upstream connections do.
+struct SharedTlsStream {
+    socket: TcpStream,
+    tls_conn: Mutex<rustls::ServerConnection>,
+}
+
+impl SharedTlsStream {
+    fn write_records(
+        tls_conn: &mut rustls::ServerConnection,
+        socket: &TcpStream,
+    ) -> io::Result<()> {
+        while tls_conn.wants_write() {
+            tls_conn.write_tls(&mut &*socket)?;
+        }
+        Ok(())
+    }
+
+    fn close(&self) {
+        let mut tls_conn = self.tls_conn.lock().unwrap();
+        tls_conn.send_close_notify();
+        let _ = Self::write_records(&mut tls_conn, &self.socket);
+    }
+}
+
+impl Read for &SharedTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut records = [0u8; TLS_READ_BUFFER_LEN];
+        loop {
+            match self.tls_conn.lock().unwrap().reader().read(buf) {
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            let len = (&self.socket).read(&mut records)?;
+            let mut tls_conn = self.tls_conn.lock().unwrap();
+            // Reading nothing tells rustls the client has gone, which the next read reports
+            let mut data = &records[..len];
+            loop {
+                tls_conn.read_tls(&mut data)?;
+                tls_conn
+                    .process_new_packets()
+                    .map_err(|error| io::Error::new(ErrorKind::InvalidData, error))?;
+                if data.is_empty() {
+                    break;
+                }
+            }
+            SharedTlsStream::write_records(&mut tls_conn, &self.socket)?;
+        }
+    }
+}
+
+impl Write for &SharedTlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut tls_conn = self.tls_conn.lock().unwrap();
+        let len = tls_conn.writer().write(buf)?;
+        SharedTlsStream::write_records(&mut tls_conn, &self.socket)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut tls_conn = self.tls_conn.lock().unwrap();
+        tls_conn.writer().flush()?;
+        SharedTlsStream::write_records(&mut tls_conn, &self.socket)
+    }
+}
+
+/// The writing half of a `SharedTlsStream`, for handing to other threads.
+struct SharedTlsWriter(Arc<SharedTlsStream>);
+
+impl Write for SharedTlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
 
 pub struct SourceServer {
-    addr: String,
-    cache: DnsCacheManager,
+    spec: SourceSpec,
+    format: StdioFormat,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    workers: usize,
+    cache: Arc<DnsCacheManager>,
+    /// Answers UDP queries and those read from TCP connections
+    query_pool: Arc<WorkerPool>,
 }
 
 impl SourceServer {
-    pub fn new<S: Into<String>>(addr: S, cache: DnsCacheManager) -> Self {
+    pub fn new(
+        spec: SourceSpec,
+        format: StdioFormat,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
+        workers: usize,
+        queue_depth: usize,
+        cache: DnsCacheManager,
+    ) -> Self {
         Self {
-            addr: addr.into(),
-            cache,
+            spec,
+            format,
+            tls_config,
+            workers,
+            cache: Arc::new(cache),
+            query_pool: WorkerPool::new(workers, queue_depth),
         }
     }
 
     pub fn start(&mut self) {
-        info!("Binding to: {}", self.addr);
-        let socket = UdpSocket::bind(self.addr.clone())
-            .unwrap_or_else(|error| panic!("Failed to bind UDP socket `{}`: {error}", self.addr));
+        match self.spec.clone() {
+            SourceSpec::Stdio => self.start_stdio(),
+            SourceSpec::Dns(addr) => {
+                self.start_tcp(addr);
+                self.start_udp(addr)
+            }
+            SourceSpec::Tls(addr) => self.start_tls(addr),
+            SourceSpec::Https { addr, path, tls } => self.start_http(addr, path, tls),
+        }
+    }
+
+    fn start_stdio(&mut self) {
+        if !self.format.is_readable() {
+            panic!(
+                "Requests cannot be read from stdin in the {} format",
+                self.format
+            );
+        }
+
+        info!("Reading requests from stdin ({} format)", self.format);
+        let mut reader = io::stdin().lock();
+        let mut writer = io::stdout().lock();
 
         loop {
-            let mut buf = [0; 512];
-            let (_number_of_bytes, src_addr) = match socket.recv_from(&mut buf) {
+            let request_bytes = match self.format.read_message(&mut reader) {
+                Ok(data) => data,
+                Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
+                    info!("Reached end of stdin");
+                    return;
+                }
+                Err(error) if error.kind() == ErrorKind::InvalidData => {
+                    error!("Failed to decode request from stdin: {error}");
+                    continue;
+                }
+                Err(error) => {
+                    error!("Failed to read data from stdin: {error}");
+                    return;
+                }
+            };
+
+            if let Some((dns_response, _)) = Self::handle_request(&self.cache, &request_bytes) {
+                if let Err(error) = self.format.write_message(&mut writer, &dns_response) {
+                    error!("Failed to write data to stdout: {error}");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Receives datagrams into a bounded queue served by the worker threads. Requests arriving
+    /// while the queue is full are dropped, and the clients retry as they would on packet loss.
+    fn start_udp(&mut self, addr: SocketAddr) {
+        info!("Binding to: {addr}");
+        let socket = UdpSocket::bind(addr)
+            .unwrap_or_else(|error| panic!("Failed to bind UDP socket `{addr}`: {error}"));
+
+        info!("Answering with up to {} worker(s)", self.workers);
+        Self::answer_datagrams(Arc::new(socket), &self.cache, &self.query_pool);
+    }
+
+    /// Hands each datagram to a worker, or drops it when they are all busy and the queue is full.
+    fn answer_datagrams(
+        socket: Arc<UdpSocket>,
+        cache: &Arc<DnsCacheManager>,
+        query_pool: &Arc<WorkerPool>,
+    ) {
+        loop {
+            let mut buf = vec![0u8; dns::EDNS_UDP_PAYLOAD_SIZE.into()];
+            let (number_of_bytes, src_addr) = match socket.recv_from(&mut buf) {
                 Ok(data) => data,
                 Err(error) => {
                     error!("Failed to receive data from socket: {error}");
                     continue;
                 }
             };
+            buf.truncate(number_of_bytes);
+
+            let cache = Arc::clone(cache);
+            let socket = Arc::clone(&socket);
+            let answer = move || Self::answer_datagram(&cache, &socket, &buf, src_addr);
+            if query_pool.try_execute(answer).is_err() {
+                warn!("Request queue is full, dropping request from {src_addr}");
+            }
+        }
+    }
+
+    fn answer_datagram(
+        cache: &DnsCacheManager,
+        socket: &UdpSocket,
+        request_bytes: &[u8],
+        src_addr: SocketAddr,
+    ) {
+        if let Some((dns_response, max_len)) = Self::handle_request(cache, request_bytes) {
+            if let Err(error) = retry(Fixed::from_millis(25).take(3), || {
+                socket.send_to(&dns_response.bytes_truncated(max_len), src_addr)
+            }) {
+                error!("Failed to send data from socket (tried 3 times): {error}");
+            }
+        }
+    }
+
+    /// Accepts DNS over TCP (RFC 7766) on the same address as UDP.
+    fn start_tcp(&mut self, addr: SocketAddr) {
+        let listener = TcpListener::bind(addr)
+            .unwrap_or_else(|error| panic!("Failed to bind TCP socket `{addr}`: {error}"));
+
+        let context = self.stream_context(None, StreamProtocol::Dns);
+        thread::spawn(move || Self::accept_connections(listener, context));
+    }
+
+    /// Accepts DNS over TLS (RFC 7858).
+    fn start_tls(&mut self, addr: SocketAddr) {
+        let tls_config = self.server_tls_config(DOT_ALPN_PROTOCOLS);
+
+        info!("Binding to: {addr} (DoT)");
+        let listener = TcpListener::bind(addr)
+            .unwrap_or_else(|error| panic!("Failed to bind TCP socket `{addr}`: {error}"));
+
+        Self::accept_connections(
+            listener,
+            self.stream_context(Some(tls_config), StreamProtocol::Dns),
+        );
+    }
+
+    /// Accepts DNS over HTTPS (RFC 8484) on `path`. Plain HTTP is meant for running behind a
+    /// reverse proxy that terminates TLS.
+    fn start_http(&mut self, addr: SocketAddr, path: String, tls: bool) {
+        let tls_config = tls.then(|| self.server_tls_config(HTTP_ALPN_PROTOCOLS));
+
+        info!("Binding to: {addr}{path} (DoH)");
+        let listener = TcpListener::bind(addr)
+            .unwrap_or_else(|error| panic!("Failed to bind TCP socket `{addr}`: {error}"));
+
+        let protocol = StreamProtocol::Http { path };
+        Self::accept_connections(listener, self.stream_context(tls_config, protocol));
+    }
+
+    fn server_tls_config(&self, alpn_protocols: &[&[u8]]) -> Arc<rustls::ServerConfig> {
+        let tls_config = self
+            .tls_config
+            .as_ref()
+            .expect("A certificate and key are required for TLS sources");
+
+        let mut tls_config = rustls::ServerConfig::clone(tls_config);
+        tls_config.alpn_protocols = alpn_protocols
+            .iter()
+            .map(|protocol| protocol.to_vec())
+            .collect();
+        Arc::new(tls_config)
+    }
+
+    fn stream_context(
+        &self,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
+        protocol: StreamProtocol,
+    ) -> StreamContext {
+        StreamContext {
+            cache: Arc::clone(&self.cache),
+            tls_config,
+            protocol,
+            idle_timeout: TCP_IDLE_TIMEOUT,
+            query_pool: Arc::clone(&self.query_pool),
+        }
+    }
+
+    /// Serves each connection on its own thread, up to `MAX_TCP_CONNECTIONS` at once.
+    fn accept_connections(listener: TcpListener, context: StreamContext) {
+        let connection_pool = WorkerPool::new(MAX_TCP_CONNECTIONS, 0);
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    error!("Failed to accept TCP connection: {error}");
+                    continue;
+                }
+            };
+
+            let context = context.clone();
+            if connection_pool
+                .try_execute(move || Self::handle_tcp_connection(&context, stream))
+                .is_err()
+            {
+                warn!("Too many TCP connections, closing a new one");
+            }
+        }
+    }
+
+    fn handle_tcp_connection(context: &StreamContext, stream: TcpStream) {
+        let peer_addr = match stream.peer_addr() {
+            Ok(peer_addr) => peer_addr,
+            Err(error) => {
+                error!("Failed to get TCP peer address: {error}");
+                return;
+            }
+        };
+        debug!("TCP connection opened: {peer_addr}");
+
+        if let Err(error) = stream
+            .set_read_timeout(Some(context.idle_timeout))
+            .and_then(|_| stream.set_write_timeout(Some(context.idle_timeout)))
+        {
+            error!("Failed to set TCP timeouts for {peer_addr}: {error}");
+            return;
+        }
+
+        let result = match &context.tls_config {
+            None => stream
+                .try_clone()
+                .and_then(|writer| Self::answer_stream(context, &stream, writer)),
+            Some(tls_config) => Self::answer_tls_stream(context, stream, tls_config),
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => {}
+            Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                debug!("TCP connection idle: {peer_addr}");
+            }
+            Err(error) => error!("Failed to serve {peer_addr}: {error}"),
+        }
+
+        debug!("TCP connection closed: {peer_addr}");
+    }
+
+    fn answer_tls_stream(
+        context: &StreamContext,
+        mut stream: TcpStream,
+        tls_config: &Arc<rustls::ServerConfig>,
+    ) -> io::Result<()> {
+        let mut tls_conn =
+            rustls::ServerConnection::new(Arc::clone(tls_config)).map_err(io::Error::other)?;
+        while tls_conn.is_handshaking() {
+            tls_conn.complete_io(&mut stream)?;
+        }
+        let is_h2 = tls_conn.alpn_protocol() == Some(b"h2");
+
+        let tls_stream = Arc::new(SharedTlsStream {
+            socket: stream,
+            tls_conn: Mutex::new(tls_conn),
+        });
+        let writer = SharedTlsWriter(Arc::clone(&tls_stream));
+        let result = match &context.protocol {
+            StreamProtocol::Http { path } if is_h2 => {
+                Self::answer_h2_stream(&context.cache, &*tls_stream, writer, path)
+            }
+            _ => Self::answer_stream(context, &*tls_stream, writer),
+        };
+
+        tls_stream.close();
+        result
+    }
+
+    fn answer_stream<R: Read, W: Write + Send + 'static>(
+        context: &StreamContext,
+        reader: R,
+        writer: W,
+    ) -> io::Result<()> {
+        match &context.protocol {
+            StreamProtocol::Dns => Self::answer_dns_stream(context, reader, writer),
+            StreamProtocol::Http { path } => {
+                Self::answer_http_stream(&context.cache, reader, writer, path)
+            }
+        }
+    }
+
+    /// Reads length prefixed queries until the client closes the stream or goes idle. Clients
+    /// may pipeline several queries without waiting, and these are answered concurrently, in
+    /// whatever order they finish (RFC 7766 6.2.1.1).
+    fn answer_dns_stream<R: Read, W: Write + Send + 'static>(
+        context: &StreamContext,
+        mut reader: R,
+        writer: W,
+    ) -> io::Result<()> {
+        let writer = Arc::new(Mutex::new(writer));
+        // Every query in flight holds a sender, so the receiver sees them all finish
+        let (done_sender, done_receiver) = mpsc::channel::<()>();
+
+        let error = loop {
+            let request_bytes = match framing::read_length_prefixed(&mut reader) {
+                Ok(request_bytes) => request_bytes,
+                Err(error) => break error,
+            };
+
+            let cache = Arc::clone(&context.cache);
+            let writer = Arc::clone(&writer);
+            let done_sender = done_sender.clone();
+            context.query_pool.execute(move || {
+                if let Some((dns_response, _)) = Self::handle_request(&cache, &request_bytes) {
+                    let response_bytes = dns_response.bytes_truncated(u16::MAX.into());
+                    let mut writer = writer.lock().unwrap();
+                    if let Err(error) =
+                        framing::write_length_prefixed(&mut *writer, &response_bytes)
+                    {
+                        debug!("Failed to write TCP response: {error}");
+                    }
+                }
+                drop(done_sender);
+            });
+        };
+
+        // The answers still being worked on are sent before the connection closes
+        drop(done_sender);
+        let _ = done_receiver.recv();
+        Err(error)
+    }
+
+    /// Answers HTTP/1.1 requests in order until the client closes the connection or goes idle.
+    fn answer_http_stream<R: Read, W: Write>(
+        cache: &DnsCacheManager,
+        reader: R,
+        mut writer: W,
+        path: &str,
+    ) -> io::Result<()> {
+        let mut reader = BufReader::new(reader);
+
+        loop {
+            let http_request = match http::read_request(&mut reader) {
+                Ok(http_request) => http_request,
+                Err(error) if error.kind() == ErrorKind::InvalidData => {
+                    warn!("Failed to parse HTTP request: {error}");
+                    let headers = [("Connection", "close".to_string())];
+                    return http::write_response(&mut writer, 400, &headers, &[]);
+                }
+                Err(error) => return Err(error),
+            };
+
+            let keep_alive = http_request.keep_alive();
+            let (status, mut headers, body) = Self::handle_http_request(cache, &http_request, path);
+            if !keep_alive {
+                headers.push(("Connection", "close".to_string()));
+            }
+
+            http::write_response(&mut writer, status, &headers, &body)?;
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
 
-            let dns_request = dns::DnsPacket::from_slice(&buf);
-            if dns_request.header.isrequest() {
-                let dns_response = self.cache.query(dns_request);
-                if let Err(error) = retry(Fixed::from_millis(25).take(3), || {
-                    socket.send_to(&dns_response.bytes(), src_addr)
-                }) {
-                    error!("Failed to send data from socket (tried 3 times): {error}");
+    /// Answers HTTP/2 requests, several at once, until the client closes the connection or goes
+    /// idle.
+    fn answer_h2_stream<R: Read + Send, W: Write + Send>(
+        cache: &Arc<DnsCacheManager>,
+        reader: R,
+        writer: W,
+        path: &str,
+    ) -> io::Result<()> {
+        let cache = Arc::clone(cache);
+        let path = path.to_string();
+        http::serve_h2(reader, writer, move |http_request| {
+            Self::handle_http_request(&cache, http_request, &path)
+        })
+    }
+
+    /// Maps a DoH request to its status, headers and body.
+    fn handle_http_request(
+        cache: &DnsCacheManager,
+        http_request: &http::HttpRequest,
+        path: &str,
+    ) -> http::HttpResponse {
+        if http_request.path() != path {
+            return (404, vec![], vec![]);
+        }
+
+        let request_bytes = match http_request.method.as_str() {
+            "GET" => {
+                // The parameter is unpadded, but tolerate clients that pad it anyway
+                let dns_param = http_request.query_param("dns").unwrap_or_default();
+                match URL_SAFE_NO_PAD.decode(dns_param.trim_end_matches('=')) {
+                    Ok(request_bytes) if !request_bytes.is_empty() => request_bytes,
+                    _ => return (400, vec![], vec![]),
                 }
             }
+            "POST" => {
+                // Parameters such as `charset` don't change the media type
+                let content_type = http_request.header("content-type").unwrap_or_default();
+                let media_type = content_type.split(';').next().unwrap_or_default().trim();
+                if !media_type.eq_ignore_ascii_case(DNS_MESSAGE_CONTENT_TYPE) {
+                    return (415, vec![], vec![]);
+                }
+                http_request.body.clone()
+            }
+            _ => return (405, vec![("Allow", "GET, POST".to_string())], vec![]),
+        };
+
+        let dns_response = match Self::handle_request(cache, &request_bytes) {
+            Some((dns_response, _)) => dns_response,
+            None => return (400, vec![], vec![]),
+        };
+
+        let mut headers = vec![("Content-Type", DNS_MESSAGE_CONTENT_TYPE.to_string())];
+        // Lets HTTP caches keep the answer no longer than its shortest TTL (RFC 8484 5.1)
+        if let Some(min_ttl) = dns_response.answer_section.iter().map(|ans| ans.ttl).min() {
+            headers.push(("Cache-Control", format!("max-age={min_ttl}")));
+        }
+
+        (200, headers, dns_response.bytes_truncated(u16::MAX.into()))
+    }
+
+    /// Answers a raw request along with the largest response the client accepts over UDP, or
+    /// returns `None` when it should be dropped.
+    fn handle_request(
+        cache: &DnsCacheManager,
+        request_bytes: &[u8],
+    ) -> Option<(dns::DnsPacket, usize)> {
+        match dns::DnsPacket::from_slice(request_bytes) {
+            Ok(dns_request) if dns_request.header.isrequest() => {
+                let max_len = dns_request.max_udp_response_len();
+                Some((cache.query(dns_request), max_len))
+            }
+            Ok(_) => None,
+            Err(error) => {
+                warn!("Failed to parse request: {error}");
+                dns::DnsPacket::new_format_error_response(request_bytes)
+                    .map(|dns_response| (dns_response, dns::DNS_UDP_MAX_LEN))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::DnsCache;
+    use crate::dest::{DestClient, DestError, DnsDest};
+    use crate::tls;
+    use std::time::Instant;
+
+    /// Answers `slow.test` only once the test says so, and everything else straight away.
+    struct GatedDest {
+        release: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl DnsDest for GatedDest {
+        fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+            if request.question_section[0].qname == "slow.test".parse().unwrap() {
+                let _ = self.release.lock().unwrap().recv();
+            }
+            Ok(dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR))
+        }
+    }
+
+    /// Answers every query after the same delay.
+    struct SlowDest {
+        rtt: Duration,
+    }
+
+    impl DnsDest for SlowDest {
+        fn query(&self, request: dns::DnsPacket) -> Result<dns::DnsPacket, DestError> {
+            thread::sleep(self.rtt);
+            Ok(dns::DnsPacket::new_response(&request, dns::RCODE_NOERROR))
+        }
+    }
+
+    fn cache_manager(dest: impl DnsDest + Send + Sync + 'static) -> Arc<DnsCacheManager> {
+        let dest_client = DestClient::with_dest(Box::new(dest));
+        Arc::new(DnsCacheManager::new(DnsCache::new(), dest_client))
+    }
+
+    fn query(qname: &str) -> dns::DnsPacket {
+        let question = dns::DnsQuestionSection {
+            qname: qname.parse().unwrap(),
+            qtype: dns::TYPE_A,
+            qclass: dns::CLASS_IN,
+        };
+        dns::DnsPacket::new_with_questions(vec![question])
+    }
+
+    /// Serves `protocol` over TCP on a loopback port.
+    fn spawn_tcp_server(
+        cache: Arc<DnsCacheManager>,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
+        protocol: StreamProtocol,
+        idle_timeout: Duration,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let context = StreamContext {
+            cache,
+            tls_config,
+            protocol,
+            idle_timeout,
+            query_pool: WorkerPool::new(4, 4),
+        };
+        thread::spawn(move || SourceServer::accept_connections(listener, context));
+        addr
+    }
+
+    fn read_response<R: Read>(reader: &mut R) -> dns::DnsPacket {
+        dns::DnsPacket::from_slice(&framing::read_length_prefixed(reader).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn source_spec_parse() {
+        let valid = [
+            ("-", "-"),
+            ("127.0.0.1", "127.0.0.1:53"),
+            ("[::1]", "[::1]:53"),
+            ("[::1]:5353", "[::1]:5353"),
+            ("tls://[::1]", "tls://[::1]:853"),
+            ("https://0.0.0.0", "https://0.0.0.0:443/dns-query"),
+            (
+                "http://127.0.0.1:8080/resolve",
+                "http://127.0.0.1:8080/resolve",
+            ),
+        ];
+        for (source, canonical) in valid {
+            let source_spec = SourceSpec::from_str(source).unwrap();
+            assert_eq!(source_spec.to_string(), canonical);
+            assert_eq!(SourceSpec::from_str(canonical), Ok(source_spec));
+        }
+
+        let invalid = [
+            "",
+            "localhost",
+            "127.0.0.1:99999",
+            "tls://",
+            "quic://127.0.0.1",
+            "https://[::1/dns-query",
+        ];
+        for source in invalid {
+            assert!(SourceSpec::from_str(source).is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn udp_answers_concurrently_and_drops_overflow() {
+        const WORKERS: usize = 4;
+        const QUEUE_DEPTH: usize = 2;
+        let rtt = Duration::from_millis(300);
+
+        let cache = cache_manager(SlowDest { rtt });
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let query_pool = WorkerPool::new(WORKERS, QUEUE_DEPTH);
+        thread::spawn(move || {
+            SourceServer::answer_datagrams(Arc::new(socket), &cache, &query_pool)
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(rtt * 4)).unwrap();
+        let requests: Vec<dns::DnsPacket> = (0..WORKERS + QUEUE_DEPTH + 1)
+            .map(|index| query(&format!("q{index}.test")))
+            .collect();
+        let start = Instant::now();
+        for request in &requests {
+            client.send_to(&request.bytes(), addr).unwrap();
+        }
+
+        let mut buf = [0u8; 512];
+        let mut answered: Vec<usize> = Vec::new();
+        while let Ok((len, _)) = client.recv_from(&mut buf) {
+            let response = dns::DnsPacket::from_slice(&buf[..len]).unwrap();
+            let index = requests
+                .iter()
+                .position(|request| response.is_response_to(request))
+                .unwrap();
+            answered.push(index);
+            // The first batch runs at once rather than one after another
+            if answered.len() == WORKERS {
+                assert!(start.elapsed() < rtt * 2, "took {:?}", start.elapsed());
+            }
+        }
+
+        // The queued ones follow, and the one more than the queue holds is dropped
+        answered.sort();
+        assert_eq!(answered, (0..WORKERS + QUEUE_DEPTH).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn tcp_answers_pipelined_queries_concurrently() {
+        let (release_sender, release_receiver) = mpsc::channel();
+        let cache = cache_manager(GatedDest {
+            release: Mutex::new(release_receiver),
+        });
+        let addr = spawn_tcp_server(cache, None, StreamProtocol::Dns, TCP_IDLE_TIMEOUT);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let slow_query = query("slow.test");
+        let fast_query = query("fast.test");
+        let mut requests: Vec<u8> = Vec::new();
+        framing::write_length_prefixed(&mut requests, &slow_query.bytes()).unwrap();
+        framing::write_length_prefixed(&mut requests, &fast_query.bytes()).unwrap();
+        stream.write_all(&requests).unwrap();
+
+        // The fast answer overtakes the slow one, which is still waiting upstream
+        let response = read_response(&mut stream);
+        assert_eq!(response.question_section, fast_query.question_section);
+
+        release_sender.send(()).unwrap();
+        let response = read_response(&mut stream);
+        assert_eq!(response.question_section, slow_query.question_section);
+    }
+
+    #[test]
+    fn tcp_closes_idle_connections() {
+        let (_release_sender, release_receiver) = mpsc::channel();
+        let cache = cache_manager(GatedDest {
+            release: Mutex::new(release_receiver),
+        });
+        let addr = spawn_tcp_server(cache, None, StreamProtocol::Dns, Duration::from_millis(100));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        framing::write_length_prefixed(&mut stream, &query("fast.test").bytes()).unwrap();
+        read_response(&mut stream);
+
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn tls_handshakes_and_answers() {
+        let (_release_sender, release_receiver) = mpsc::channel();
+        let cache = cache_manager(GatedDest {
+            release: Mutex::new(release_receiver),
+        });
+        let mut server_config = tls::testing::server_config();
+        server_config.alpn_protocols = vec![b"dot".to_vec()];
+        let addr = spawn_tcp_server(
+            cache,
+            Some(Arc::new(server_config)),
+            StreamProtocol::Dns,
+            TCP_IDLE_TIMEOUT,
+        );
+
+        let mut client_config = tls::testing::client_config();
+        client_config.alpn_protocols = vec![b"dot".to_vec()];
+        let tls_conn =
+            rustls::ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap())
+                .unwrap();
+        let socket = TcpStream::connect(addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut stream = rustls::StreamOwned::new(tls_conn, socket);
+
+        let request = query("fast.test");
+        framing::write_length_prefixed(&mut stream, &request.bytes()).unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.is_response_to(&request));
+        assert_eq!(stream.conn.alpn_protocol(), Some(&b"dot"[..]));
+    }
+
+    #[test]
+    fn http_post_checks_media_type() {
+        let (_release_sender, release_receiver) = mpsc::channel();
+        let cache = cache_manager(GatedDest {
+            release: Mutex::new(release_receiver),
+        });
+        let request = query("post.test");
+
+        for (content_type, status) in [
+            ("application/dns-message", 200),
+            ("Application/DNS-Message ; charset=utf-8", 200),
+            ("application/dns-message;q=1", 200),
+            ("text/plain; charset=utf-8", 415),
+            ("application/dns-message-extra", 415),
+        ] {
+            let mut input = format!(
+                "POST {DEFAULT_DOH_PATH} HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+                request.bytes().len()
+            )
+            .into_bytes();
+            input.extend_from_slice(&request.bytes());
+            let http_request = http::read_request(&mut &input[..]).unwrap();
+
+            let (response_status, _, body) =
+                SourceServer::handle_http_request(&cache, &http_request, DEFAULT_DOH_PATH);
+            assert_eq!(response_status, status, "{content_type}");
+            if status == 200 {
+                let response = dns::DnsPacket::from_slice(&body).unwrap();
+                assert!(response.is_response_to(&request));
+            }
+        }
+    }
+
+    #[test]
+    fn https_answers_http2_and_http1() {
+        let (_release_sender, release_receiver) = mpsc::channel();
+        let cache = cache_manager(GatedDest {
+            release: Mutex::new(release_receiver),
+        });
+        let mut server_config = tls::testing::server_config();
+        server_config.alpn_protocols = HTTP_ALPN_PROTOCOLS
+            .iter()
+            .map(|protocol| protocol.to_vec())
+            .collect();
+        let protocol = StreamProtocol::Http {
+            path: DEFAULT_DOH_PATH.to_string(),
+        };
+        let addr = spawn_tcp_server(
+            cache,
+            Some(Arc::new(server_config)),
+            protocol,
+            TCP_IDLE_TIMEOUT,
+        );
+
+        // Padded, with the padding percent-encoded as some clients do
+        let request = query("h2.test");
+        let dns_param = base64::engine::general_purpose::URL_SAFE
+            .encode(request.bytes())
+            .replace('=', "%3D");
+        assert!(dns_param.ends_with("%3D"));
+        let url = format!(
+            "https://localhost:{}{DEFAULT_DOH_PATH}?dns={dns_param}",
+            addr.port()
+        );
+
+        for (http1_only, version) in [
+            (false, reqwest::Version::HTTP_2),
+            (true, reqwest::Version::HTTP_11),
+        ] {
+            let client_builder = reqwest::blocking::Client::builder()
+                .use_rustls_tls()
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(
+                    reqwest::Certificate::from_pem(tls::testing::LOCALHOST_CERT_PEM).unwrap(),
+                )
+                .resolve("localhost", addr);
+            let client_builder = match http1_only {
+                true => client_builder.http1_only(),
+                false => client_builder,
+            };
+            let response = client_builder.build().unwrap().get(&url).send().unwrap();
+
+            assert_eq!(response.version(), version);
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers()["content-type"], DNS_MESSAGE_CONTENT_TYPE);
+            let response = dns::DnsPacket::from_slice(&response.bytes().unwrap()).unwrap();
+            assert!(response.is_response_to(&request));
         }
     }
 }